@@ -1,39 +1,122 @@
-use crate::{ProofOfWork, Transaction};
+use crate::proof_of_work::initial_bits;
+use crate::{Error, MerkleProof, MerkleTree, ProofOfWork, Transaction};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
+use std::collections::HashSet;
+
+/// 区块版本
+const BLOCK_VERSION: u32 = 1;
+
+/// 区块序列化后的最大字节数
+pub const MAX_BLOCK_SIZE: usize = 1000 * 1000;
+
+/// 区块头，区块哈希由区块头计算得到
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    version: u32,           // 区块版本
+    pre_block_hash: String, // 上一区块的哈希值
+    merkle_root: Vec<u8>,   // 交易的默克尔根
+    timestamp: i64,         // 区块时间戳
+    bits: u32,              // 压缩格式的难度目标
+    nonce: i64,             // 计数器
+    height: usize,          // 区块链中节点的高度
+}
+
+impl BlockHeader {
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_pre_block_hash(&self) -> String {
+        self.pre_block_hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> &[u8] {
+        self.merkle_root.as_slice()
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> BlockHeader {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap().to_vec()
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: i64,                 // 区块时间戳
-    pre_block_hash: String,         // 上一区块的哈希值
+    header: BlockHeader,            // 区块头
     hash: String,                   // 当前区块的哈希值
     transactions: Vec<Transaction>, // 交易数据
-    nonce: i64,                     // 计数器
-    height: usize,                  // 区块链中节点的高度
 }
 
 impl Block {
     /// 新建一个区块
-    pub fn new_block(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Block {
-        let mut block = Block {
-            timestamp: crate::current_timestamp(),
+    pub fn new_block(
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        bits: u32,
+    ) -> Block {
+        Self::new_block_at(
             pre_block_hash,
+            transactions,
+            height,
+            bits,
+            crate::current_timestamp(),
+        )
+    }
+
+    /// 使用指定的时间戳新建一个区块
+    pub fn new_block_at(
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        bits: u32,
+        timestamp: i64,
+    ) -> Block {
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                pre_block_hash,
+                merkle_root: vec![],
+                timestamp,
+                bits,
+                nonce: 0,
+                height,
+            },
             hash: String::new(),
             transactions: transactions.to_vec(),
-            nonce: 0,
-            height,
         };
-        // 挖矿计算哈希
-        let pow = ProofOfWork::new_proof_of_work(block.clone());
+        block.header.merkle_root = block.hash_transactions();
+        // 挖矿只需要计算区块头的哈希
+        let pow = ProofOfWork::new_proof_of_work(block.header.clone());
         let (nonce, hash) = pow.run();
-        block.nonce = nonce;
+        block.header.nonce = nonce;
         block.hash = hash;
         return block;
     }
 
-    /// 从字节数组反序列化
-    pub fn deserialize(bytes: &[u8]) -> Block {
-        bincode::deserialize(bytes).unwrap()
+    /// 从字节数组（如网络数据或数据库中可能损坏的数据）反序列化
+    pub fn try_deserialize(bytes: &[u8]) -> crate::Result<Block> {
+        Ok(bincode::deserialize(bytes)?)
     }
 
     /// 区块序列化
@@ -44,24 +127,42 @@ impl Block {
     /// 生成创世块
     pub fn generate_genesis_block(transaction: &Transaction) -> Block {
         let transactions = vec![transaction.clone()];
-        return Block::new_block(String::from("None"), &transactions, 0);
+        return Block::new_block(String::from("None"), &transactions, 0, initial_bits());
     }
 
-    /// 计算区块里所有交易的哈希
+    /// 计算区块里所有交易的默克尔根
     pub fn hash_transactions(&self) -> Vec<u8> {
-        let mut txhashs = vec![];
-        for transaction in &self.transactions {
-            txhashs.extend(transaction.get_id());
-        }
-        crate::sha256_digest(txhashs.as_slice())
+        self.merkle_tree().root()
+    }
+
+    fn merkle_tree(&self) -> MerkleTree {
+        let txids: Vec<Vec<u8>> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.get_id_bytes())
+            .collect();
+        MerkleTree::new(txids.as_slice())
+    }
+
+    /// 生成交易包含在区块中的默克尔证明
+    pub fn merkle_proof(&self, txid: &[u8]) -> Option<MerkleProof> {
+        let index = self
+            .transactions
+            .iter()
+            .position(|tx| tx.get_id().eq(txid))?;
+        self.merkle_tree().proof(index)
     }
 
     pub fn get_transactions(&self) -> &[Transaction] {
         self.transactions.as_slice()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_pre_block_hash(&self) -> String {
-        self.pre_block_hash.clone()
+        self.header.get_pre_block_hash()
     }
 
     pub fn get_hash(&self) -> &str {
@@ -73,11 +174,84 @@ impl Block {
     }
 
     pub fn get_timestamp(&self) -> i64 {
-        self.timestamp
+        self.header.get_timestamp()
     }
 
     pub fn get_height(&self) -> usize {
-        self.height
+        self.header.get_height()
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.header.get_nonce()
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
+    }
+
+    pub fn get_merkle_root(&self) -> &[u8] {
+        self.header.get_merkle_root()
+    }
+
+    /// 校验区块自身的有效性（不依赖链上状态）
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.transactions.is_empty() {
+            return Err(Error::InvalidBlock(String::from(
+                "block has no transactions",
+            )));
+        }
+        if self.serialize().len() > MAX_BLOCK_SIZE {
+            return Err(Error::InvalidBlock(String::from(
+                "block exceeds the maximum block size",
+            )));
+        }
+        let coinbase_count = self
+            .transactions
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .count();
+        if coinbase_count != 1 {
+            return Err(Error::InvalidBlock(format!(
+                "block has {} coinbase transactions",
+                coinbase_count
+            )));
+        }
+        let mut txids = HashSet::new();
+        for tx in &self.transactions {
+            // 重复的交易会得到相同的默克尔根，需要拒绝
+            if !txids.insert(tx.get_id()) {
+                return Err(Error::InvalidBlock(String::from(
+                    "block has duplicate transactions",
+                )));
+            }
+            if !tx.is_id_valid() {
+                return Err(Error::InvalidBlock(String::from(
+                    "transaction id does not match its content",
+                )));
+            }
+            if tx.get_vout().iter().any(|out| out.get_value() < 0) {
+                return Err(Error::InvalidBlock(String::from(
+                    "transaction has a negative output value",
+                )));
+            }
+        }
+        if self.header.merkle_root.ne(&self.hash_transactions()) {
+            return Err(Error::InvalidBlock(String::from(
+                "merkle root does not match transactions",
+            )));
+        }
+        if !self.verify_pow() {
+            return Err(Error::InvalidBlock(String::from(
+                "proof of work is not valid",
+            )));
+        }
+        Ok(())
+    }
+
+    /// 校验区块的 nonce 和哈希满足工作量证明
+    pub fn verify_pow(&self) -> bool {
+        let pow = ProofOfWork::new_proof_of_work(self.header.clone());
+        pow.validate(self.hash.as_str())
     }
 }
 
@@ -90,7 +264,8 @@ impl From<Block> for IVec {
 
 #[cfg(test)]
 mod tests {
-    use super::Block;
+    use super::{Block, BlockHeader};
+    use crate::proof_of_work::initial_bits;
     use crate::Transaction;
 
     #[test]
@@ -99,20 +274,75 @@ mod tests {
             String::from("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"),
             &vec![],
             0,
+            initial_bits(),
         );
         println!("new block hash is {}", block.hash)
     }
 
     #[test]
     fn test_block_serialize() {
-        let tx = Transaction::new_coinbase_tx("Genesis");
+        let tx = Transaction::new_coinbase_tx("Genesis", 0, 0);
         let block = Block::new_block(
             String::from("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"),
             &vec![tx],
             0,
+            initial_bits(),
         );
         let block_bytes = block.serialize();
-        let desc_block = Block::deserialize(&block_bytes[..]);
-        assert_eq!(block.hash, desc_block.hash)
+        let desc_block = Block::try_deserialize(&block_bytes[..]).unwrap();
+        assert_eq!(block.hash, desc_block.hash);
+
+        // 截断的数据无法反序列化
+        assert!(Block::try_deserialize(&block_bytes[..block_bytes.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        let mut block = Block::new_block(
+            String::from("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"),
+            &vec![tx],
+            1,
+            initial_bits(),
+        );
+        assert!(block.validate().is_ok());
+
+        block.header.nonce += 1;
+        assert!(block.validate().is_err());
+    }
+
+    #[test]
+    fn test_verify_pow() {
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        let mut block = Block::new_block(String::from("None"), &vec![tx], 0, initial_bits());
+        assert!(block.verify_pow());
+
+        // 哈希被篡改
+        block.hash =
+            String::from("0000000000000000000000000000000000000000000000000000000000000000");
+        assert!(!block.verify_pow());
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let txs = vec![
+            Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0),
+            Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0),
+            Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0),
+        ];
+        let block = Block::new_block(String::from("None"), &txs, 0, initial_bits());
+        let proof = block.merkle_proof(txs[2].get_id()).unwrap();
+        assert_eq!(proof.get_index(), 2);
+        assert!(proof.verify(block.get_merkle_root(), txs.len()));
+    }
+
+    #[test]
+    fn test_block_header() {
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        let block = Block::new_block(String::from("None"), &vec![tx], 0, initial_bits());
+        let header_bytes = block.get_header().serialize();
+        let header = BlockHeader::deserialize(header_bytes.as_slice());
+        assert_eq!(header.get_merkle_root(), block.get_merkle_root());
+        assert_eq!(header.get_nonce(), block.get_nonce());
     }
 }
@@ -1,69 +1,428 @@
-use crate::transaction::TXOutput;
-use crate::{Block, Transaction};
+use crate::proof_of_work::{block_work, retarget_bits, DIFFICULTY_ADJUSTMENT_INTERVAL};
+use crate::transaction::{get_block_subsidy, TXOutput};
+use crate::utxo_set::UTXOEntry;
+use crate::wallet::hash_pub_key;
+use crate::{
+    Block, BlockHeader, Error, MerkleProof, SledStorage, Storage, Transaction, UTXOSet, WriteBatch,
+    GLOBAL_CONFIG,
+};
 use data_encoding::HEXLOWER;
-use sled::transaction::TransactionResult;
-use sled::{Db, Tree};
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::sync::{Arc, RwLock};
+use log::info;
+use num_bigint::BigInt;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// 区块链数据库在数据目录下的子目录
+const DB_DIR: &str = "data";
 
 const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
 const BLOCKS_TREE: &str = "blocks";
+/// 数据库格式版本，保存在 blocks 树中，区块格式改变时递增
+const DB_VERSION_KEY: &str = "db_version";
+const DB_VERSION: u32 = 1;
+/// 区块头 ( K -> block_hash, V -> BlockHeader )
+const HEADERS_TREE: &str = "headers";
+/// 每个区块所在分支从创世块开始的累计工作量 ( K -> block_hash, V -> BigInt )
+const CHAINWORK_TREE: &str = "chainwork";
+/// 主链交易索引 ( K -> txid, V -> (block_hash, 交易在区块中的位置) )
+const TXINDEX_TREE: &str = "txindex";
+/// 交易索引已建立的标记，保存在 blocks 树中
+const TXINDEX_KEY: &str = "txindex";
+/// 主链高度索引 ( K -> 大端序的区块高度, V -> block_hash )
+const HEIGHTS_TREE: &str = "heights";
+/// 主链地址索引，包括地址收款和付款的交易 ( K -> pub_key_hash + 大端序的区块高度 + 交易在区块中的位置, V -> txid )
+const ADDRINDEX_TREE: &str = "addrindex";
+/// 地址索引已建立的标记，保存在 blocks 树中
+const ADDRINDEX_KEY: &str = "addrindex";
+
+/// 计算中位时间时使用的区块数量，新区块的时间戳必须大于之前这些区块时间戳的中位数
+const MEDIAN_TIME_SPAN: usize = 11;
+/// 区块时间戳最多比本地时间超前 2 小时，单位：ms
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60 * 1000;
+
+/// 地址索引中的一条记录 (区块高度, 交易在区块中的位置, txid)
+type AddressIndexEntry = (usize, usize, Vec<u8>);
 
 #[derive(Clone)]
 pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>, // hash of last block
-    db: Db,
+    storage: Arc<dyn Storage>,
+    chain_lock: Arc<Mutex<()>>, // 校验区块与修改最新区块需要在同一个锁内完成
 }
 
 impl Blockchain {
-    /// 创建新的区块链
+    /// 在默认数据目录创建新的区块链
     pub fn create_blockchain(genesis_address: &str) -> Blockchain {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+        match Self::create(GLOBAL_CONFIG.get_data_dir(), genesis_address) {
+            Ok(blockchain) => blockchain,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// 在指定数据目录创建新的区块链，区块链已存在时直接打开
+    pub fn create<P: AsRef<Path>>(data_dir: P, genesis_address: &str) -> crate::Result<Blockchain> {
+        let storage = SledStorage::open(data_dir.as_ref().join(DB_DIR))?;
+        Self::create_with_storage(Arc::new(storage), genesis_address)
+    }
 
-        let data = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
-        let tip_hash;
+    /// 在指定存储中创建新的区块链，区块链已存在时直接打开
+    pub fn create_with_storage(
+        storage: Arc<dyn Storage>,
+        genesis_address: &str,
+    ) -> crate::Result<Blockchain> {
+        let data = storage.get(BLOCKS_TREE, TIP_BLOCK_HASH_KEY.as_bytes())?;
         if data.is_none() {
-            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address);
+            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address, 0, 0);
             let block = Block::generate_genesis_block(&coinbase_tx);
-            Self::update_blocks_tree(&blocks_tree, &block);
-            tip_hash = String::from(block.get_hash());
-        } else {
-            tip_hash = String::from_utf8(data.unwrap().to_vec()).unwrap();
-        }
-        Blockchain {
-            tip_hash: Arc::new(RwLock::new(tip_hash)),
-            db,
+            let mut batch = Self::block_batch(&block, &block_work(block.get_bits()));
+            batch.insert(BLOCKS_TREE, TIP_BLOCK_HASH_KEY, block.get_hash());
+            batch.insert(BLOCKS_TREE, DB_VERSION_KEY, DB_VERSION.to_be_bytes());
+            storage.write_batch(batch)?;
         }
+        Self::open_with_storage(storage)
     }
 
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) {
+    /// 保存区块、区块头及其累计工作量的修改
+    fn block_batch(block: &Block, chain_work: &BigInt) -> WriteBatch {
         let block_hash = block.get_hash();
-        let _: TransactionResult<(), ()> = blocks_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block_hash, block.clone());
-            let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block_hash);
-            Ok(())
-        });
+        let mut batch = WriteBatch::new();
+        batch.insert(BLOCKS_TREE, block_hash, block.serialize());
+        batch.insert(HEADERS_TREE, block_hash, block.get_header().serialize());
+        batch.insert(CHAINWORK_TREE, block_hash, chain_work.to_signed_bytes_be());
+        batch
     }
 
     /// 创建区块链实例
     pub fn new_blockchain() -> Blockchain {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
-        let tip_bytes = blocks_tree
-            .get(TIP_BLOCK_HASH_KEY)
-            .unwrap()
-            .expect("No existing blockchain found. Create one first.");
-        let tip_hash = String::from_utf8(tip_bytes.to_vec()).unwrap();
-        Blockchain {
+        match Self::try_new_blockchain() {
+            Ok(blockchain) => blockchain,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// 打开默认数据目录中的区块链，区块链不存在或数据库无法打开时返回错误
+    pub fn try_new_blockchain() -> crate::Result<Blockchain> {
+        Self::open(GLOBAL_CONFIG.get_data_dir())
+    }
+
+    /// 打开指定数据目录中的区块链
+    pub fn open<P: AsRef<Path>>(data_dir: P) -> crate::Result<Blockchain> {
+        let db_path = data_dir.as_ref().join(DB_DIR);
+        if !db_path.exists() {
+            return Err(Error::BlockchainNotFound);
+        }
+        let storage = SledStorage::open(db_path)?;
+        Self::open_with_storage(Arc::new(storage))
+    }
+
+    /// 打开指定存储中的区块链，数据库由不兼容的旧版本创建时返回错误
+    pub fn open_with_storage(storage: Arc<dyn Storage>) -> crate::Result<Blockchain> {
+        let tip_bytes = storage
+            .get(BLOCKS_TREE, TIP_BLOCK_HASH_KEY.as_bytes())?
+            .ok_or(Error::BlockchainNotFound)?;
+        // 旧版本的区块没有单独保存的区块头，无法迁移，只能重新创建或下载区块链
+        let db_version = storage.get(BLOCKS_TREE, DB_VERSION_KEY.as_bytes())?;
+        if db_version.as_deref() != Some(DB_VERSION.to_be_bytes().as_slice()) {
+            return Err(Error::IncompatibleDatabase);
+        }
+        let tip_hash = String::from_utf8(tip_bytes)
+            .map_err(|_| Error::Corrupted(String::from("tip block hash is not valid")))?;
+        let blockchain = Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
-            db,
+            storage,
+            chain_lock: Arc::new(Mutex::new(())),
+        };
+        blockchain.init_indexes()?;
+        Ok(blockchain)
+    }
+
+    /// 高度索引与最新区块不一致时重建高度索引；配置要求交易索引但尚未建立时，建立交易索引
+    /// 最新区块无法读取时不修改索引，由 verify_chain 报告并修复
+    fn init_indexes(&self) -> crate::Result<()> {
+        let tip_hash = self.get_tip_hash();
+        let best_height = match self.find_header(tip_hash.as_str()) {
+            Some(header) => header.get_height(),
+            None => return Ok(()),
+        };
+        if self.get_block_hash(best_height).as_ref() != Some(&tip_hash) {
+            let count = self.reindex_heights()?;
+            info!("Built the height index of {} blocks", count);
+        }
+        if GLOBAL_CONFIG.is_txindex() && !self.has_txindex()? {
+            let count = self.reindex_transactions()?;
+            info!("Built the transaction index of {} transactions", count);
+        }
+        if GLOBAL_CONFIG.is_addrindex() && !self.has_addrindex()? {
+            let count = self.reindex_addresses()?;
+            info!("Built the address index of {} addresses", count);
         }
+        Ok(())
     }
 
-    pub fn get_db(&self) -> &Db {
-        &self.db
+    /// 是否已建立交易索引，建立后区块连接和断开时会同步维护索引
+    pub fn has_txindex(&self) -> crate::Result<bool> {
+        Ok(self
+            .storage
+            .get(BLOCKS_TREE, TXINDEX_KEY.as_bytes())?
+            .is_some())
+    }
+
+    /// 重建主链的交易索引，返回索引的交易数量
+    pub fn reindex_transactions(&self) -> crate::Result<usize> {
+        // 先移除标记再清空索引，重建中途失败时不会留下标记为已建立的不完整索引
+        self.storage.remove(BLOCKS_TREE, TXINDEX_KEY.as_bytes())?;
+        self.storage.clear(TXINDEX_TREE)?;
+        let mut batch = WriteBatch::new();
+        let mut count = 0;
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next()? {
+            Self::index_transactions(&mut batch, &block);
+            count += block.get_transactions().len();
+        }
+        batch.insert(BLOCKS_TREE, TXINDEX_KEY, [1u8]);
+        self.storage.write_batch(batch)?;
+        Ok(count)
+    }
+
+    fn index_transactions(batch: &mut WriteBatch, block: &Block) {
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let location = bincode::serialize(&(block.get_hash(), position)).unwrap();
+            batch.insert(TXINDEX_TREE, tx.get_id(), location);
+        }
+    }
+
+    /// 是否已建立地址索引，建立后区块连接和断开时会同步维护索引
+    pub fn has_addrindex(&self) -> crate::Result<bool> {
+        Ok(self
+            .storage
+            .get(BLOCKS_TREE, ADDRINDEX_KEY.as_bytes())?
+            .is_some())
+    }
+
+    /// 重建主链的地址索引，返回索引的地址数量
+    pub fn reindex_addresses(&self) -> crate::Result<usize> {
+        // 先移除标记再清空索引，重建中途失败时不会留下标记为已建立的不完整索引
+        self.storage.remove(BLOCKS_TREE, ADDRINDEX_KEY.as_bytes())?;
+        self.storage.clear(ADDRINDEX_TREE)?;
+        let mut batch = WriteBatch::new();
+        let mut pub_key_hashes = HashSet::new();
+        for block in self.range_iterator(0, usize::MAX) {
+            let block = block?;
+            for (pub_key_hash, (height, position, txid)) in Self::index_addresses(&block) {
+                batch.insert(
+                    ADDRINDEX_TREE,
+                    address_index_key(pub_key_hash.as_slice(), height, position),
+                    txid,
+                );
+                pub_key_hashes.insert(pub_key_hash);
+            }
+        }
+        batch.insert(BLOCKS_TREE, ADDRINDEX_KEY, [1u8]);
+        self.storage.write_batch(batch)?;
+        Ok(pub_key_hashes.len())
+    }
+
+    /// 找出区块中每笔交易收款和付款的地址，返回 (公钥哈希, 地址索引记录)
+    fn index_addresses(block: &Block) -> Vec<(Vec<u8>, AddressIndexEntry)> {
+        let mut index = vec![];
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let mut pub_key_hashes: HashSet<Vec<u8>> = tx
+                .get_vout()
+                .iter()
+                .map(|out| out.get_pub_key_hash().to_vec())
+                .collect();
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    pub_key_hashes.insert(hash_pub_key(vin.get_pub_key()));
+                }
+            }
+            for pub_key_hash in pub_key_hashes {
+                index.push((
+                    pub_key_hash,
+                    (block.get_height(), position, tx.get_id().to_vec()),
+                ));
+            }
+        }
+        index
+    }
+
+    /// 查询地址索引中公钥哈希对应的记录，按交易在主链中的顺序排列
+    fn get_address_index(&self, pub_key_hash: &[u8]) -> crate::Result<Vec<AddressIndexEntry>> {
+        self.storage
+            .scan_prefix(ADDRINDEX_TREE, pub_key_hash)?
+            .map(|item| {
+                let (key, txid) = item?;
+                let (height, position) = parse_address_index_key(&key[pub_key_hash.len()..]);
+                Ok((height, position, txid))
+            })
+            .collect()
+    }
+
+    /// 通过地址索引查询地址的交易历史，按交易在主链中的顺序排列，并计算每笔交易后的余额
+    /// 索引指向的区块或交易不在主链中时返回 Error::Corrupted
+    pub fn get_address_history(
+        &self,
+        pub_key_hash: &[u8],
+    ) -> crate::Result<Vec<AddressHistoryEntry>> {
+        let entries = self.get_address_index(pub_key_hash)?;
+        let mut history = vec![];
+        let mut balance = 0;
+        // 地址收到的输出，付款交易的输入只能引用其中的输出
+        let mut received_outputs: HashMap<Vec<u8>, Vec<TXOutput>> = HashMap::new();
+        for (height, position, txid) in entries {
+            let block = self.get_block_by_height(height)?.ok_or_else(|| {
+                Error::Corrupted(format!("indexed block at height {} is not found", height))
+            })?;
+            let tx = block.get_transactions().get(position).ok_or_else(|| {
+                Error::Corrupted(format!(
+                    "indexed transaction {} is not found in block {}",
+                    HEXLOWER.encode(txid.as_slice()),
+                    block.get_hash()
+                ))
+            })?;
+            let mut spent = 0;
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    if hash_pub_key(vin.get_pub_key()).ne(pub_key_hash) {
+                        continue;
+                    }
+                    if let Some(out) = received_outputs
+                        .get(vin.get_txid())
+                        .and_then(|outs| outs.get(vin.get_vout()))
+                    {
+                        spent += out.get_value();
+                    }
+                }
+            }
+            let received: i32 = tx
+                .get_vout()
+                .iter()
+                .filter(|out| out.is_locked_with_key(pub_key_hash))
+                .map(|out| out.get_value())
+                .sum();
+            received_outputs.insert(txid.clone(), tx.get_vout().to_vec());
+            balance += received - spent;
+            history.push(AddressHistoryEntry {
+                txid,
+                height,
+                received,
+                spent,
+                balance,
+            });
+        }
+        Ok(history)
+    }
+
+    /// 重建主链的高度索引，返回索引的区块数量
+    fn reindex_heights(&self) -> crate::Result<usize> {
+        self.storage.clear(HEIGHTS_TREE)?;
+        let mut batch = WriteBatch::new();
+        let mut count = 0;
+        let mut iterator = self.header_iterator();
+        while let Some((block_hash, header)) = iterator.next() {
+            batch.insert(HEIGHTS_TREE, height_key(header.get_height()), block_hash);
+            count += 1;
+        }
+        self.storage.write_batch(batch)?;
+        Ok(count)
+    }
+
+    /// 从当前主链重建高度索引，以及已建立的交易索引和地址索引
+    fn rebuild_indexes(&self) -> crate::Result<()> {
+        self.reindex_heights()?;
+        if self.has_txindex()? {
+            self.reindex_transactions()?;
+        }
+        if self.has_addrindex()? {
+            self.reindex_addresses()?;
+        }
+        Ok(())
+    }
+
+    /// 区块连接到主链，更新高度索引，并将其中的交易加入交易索引和地址索引
+    fn connect_indexes(&self, batch: &mut WriteBatch, block: &Block) -> crate::Result<()> {
+        batch.insert(
+            HEIGHTS_TREE,
+            height_key(block.get_height()),
+            block.get_hash(),
+        );
+        if self.has_txindex()? {
+            Self::index_transactions(batch, block);
+        }
+        if self.has_addrindex()? {
+            for (pub_key_hash, (height, position, txid)) in Self::index_addresses(block) {
+                batch.insert(
+                    ADDRINDEX_TREE,
+                    address_index_key(pub_key_hash.as_slice(), height, position),
+                    txid,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// 区块从主链断开，更新高度索引，并将其中的交易移出交易索引和地址索引
+    fn disconnect_indexes(&self, batch: &mut WriteBatch, block: &Block) -> crate::Result<()> {
+        batch.remove(HEIGHTS_TREE, height_key(block.get_height()));
+        if self.has_txindex()? {
+            for tx in block.get_transactions() {
+                batch.remove(TXINDEX_TREE, tx.get_id());
+            }
+        }
+        if self.has_addrindex()? {
+            for (pub_key_hash, (height, position, _)) in Self::index_addresses(block) {
+                batch.remove(
+                    ADDRINDEX_TREE,
+                    address_index_key(pub_key_hash.as_slice(), height, position),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// 将扩展最新区块的区块连接到主链，UTXO 集、索引及最新区块的修改与 batch 中已有的修改在同一个事务中写入
+    /// 写入成功后才更新内存中的最新区块
+    fn connect_block(
+        &self,
+        mut batch: WriteBatch,
+        utxo_set: &UTXOSet,
+        block: &Block,
+    ) -> crate::Result<()> {
+        utxo_set.connect_block(&mut batch, block)?;
+        self.connect_indexes(&mut batch, block)?;
+        batch.insert(BLOCKS_TREE, TIP_BLOCK_HASH_KEY, block.get_hash());
+        self.storage.write_batch(batch)?;
+        self.set_tip_hash(block.get_hash());
+        Ok(())
+    }
+
+    /// 从主链断开最新区块，UTXO 集、索引及最新区块的修改在同一个事务中写入
+    fn disconnect_block(&self, utxo_set: &UTXOSet, block: &Block) -> crate::Result<()> {
+        let mut batch = WriteBatch::new();
+        utxo_set.disconnect_block(&mut batch, block)?;
+        self.disconnect_indexes(&mut batch, block)?;
+        batch.insert(BLOCKS_TREE, TIP_BLOCK_HASH_KEY, block.get_pre_block_hash());
+        self.storage.write_batch(batch)?;
+        self.set_tip_hash(block.get_pre_block_hash().as_str());
+        Ok(())
+    }
+
+    /// 通过交易索引查找交易所在的区块及位置
+    fn find_indexed_transaction(&self, txid: &[u8]) -> crate::Result<Option<(Block, usize)>> {
+        let Some(location) = self.storage.get(TXINDEX_TREE, txid)? else {
+            return Ok(None);
+        };
+        let (block_hash, position): (String, usize) = bincode::deserialize(location.as_slice())?;
+        let block = self.get_block(block_hash.as_bytes())?.ok_or_else(|| {
+            Error::Corrupted(format!("indexed block {} is not found", block_hash))
+        })?;
+        Ok(Some((block, position)))
+    }
+
+    pub fn get_storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
     }
 
     pub fn get_tip_hash(&self) -> String {
@@ -75,242 +434,1421 @@ impl Blockchain {
         *tip_hash = String::from(new_tip_hash)
     }
 
-    /// 挖矿新区块
-    pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
-        for trasaction in transactions {
-            if trasaction.verify(self) == false {
-                panic!("ERROR: Invalid transaction")
-            }
-        }
-        let best_height = self.get_best_height();
-
-        let block = Block::new_block(self.get_tip_hash(), transactions, best_height + 1);
-        let block_hash = block.get_hash();
+    /// 挖矿新区块，新区块与来自网络的区块一样经过校验后加入区块链
+    /// 挖矿期间最新区块发生变化时丢弃新区块，返回 Error::TipChanged
+    pub fn mine_block(&self, transactions: &[Transaction]) -> crate::Result<Block> {
+        let pre_block_hash = self.get_tip_hash();
+        let pre_header = self
+            .get_block_header(pre_block_hash.as_bytes())
+            .ok_or_else(|| Error::Corrupted(format!("tip block {} is missing", pre_block_hash)))?;
+        let block = Block::new_block_at(
+            pre_block_hash.clone(),
+            transactions,
+            pre_header.get_height() + 1,
+            self.get_next_bits(&pre_header)?,
+            self.get_next_timestamp(&pre_header),
+        );
 
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        Self::update_blocks_tree(&blocks_tree, &block);
-        self.set_tip_hash(block_hash);
-        block
+        let _guard = self.chain_lock.lock().unwrap();
+        if self.get_tip_hash().ne(&pre_block_hash) {
+            return Err(Error::TipChanged);
+        }
+        self.add_block_locked(&block)?;
+        Ok(block)
     }
 
     pub fn iterator(&self) -> BlockchainIterator {
-        BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
+        BlockchainIterator::new(self.get_tip_hash(), self.storage.clone())
+    }
+
+    pub fn header_iterator(&self) -> BlockHeaderIterator {
+        BlockHeaderIterator::new(self.get_tip_hash(), self.storage.clone())
     }
 
-    /// 查找所有未花费的交易输出 ( K -> txid_hex, V -> Vec<TXOutput )
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
+    /// 查找所有未花费的交易输出 ( K -> (txid_hex, vout), V -> UTXOEntry )
+    pub fn find_utxo(&self) -> crate::Result<HashMap<(String, usize), UTXOEntry>> {
+        let mut utxo: HashMap<(String, usize), UTXOEntry> = HashMap::new();
+        let mut spent_txos: HashSet<(String, usize)> = HashSet::new();
 
         let mut iterator = self.iterator();
-        loop {
-            let option = iterator.next();
-            if option.is_none() {
-                break;
-            }
-            let block = option.unwrap();
-            'outer: for tx in block.get_transactions() {
+        while let Some(block) = iterator.next()? {
+            // 区块内的交易可以花费排在它前面的交易的输出，倒序遍历保证先记录花费再过滤输出
+            for tx in block.get_transactions().iter().rev() {
                 let txid_hex = HEXLOWER.encode(tx.get_id());
                 for (idx, out) in tx.get_vout().iter().enumerate() {
+                    let outpoint = (txid_hex.clone(), idx);
                     // 过滤已花费的输出
-                    if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                        for spend_out_idx in outs {
-                            if idx.eq(spend_out_idx) {
-                                continue 'outer;
-                            }
-                        }
-                    }
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
+                    if spent_txos.contains(&outpoint) {
+                        continue;
                     }
+                    let entry = UTXOEntry::new(out.clone(), block.get_height(), tx.is_coinbase());
+                    utxo.insert(outpoint, entry);
                 }
                 if tx.is_coinbase() {
                     continue;
                 }
                 // 在输入中查找已花费输出
                 for txin in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(txin.get_txid());
-                    if spent_txos.contains_key(txid_hex.as_str()) {
-                        spent_txos
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(txin.get_vout());
-                    } else {
-                        spent_txos.insert(txid_hex, vec![txin.get_vout()]);
-                    }
+                    spent_txos.insert((HEXLOWER.encode(txin.get_txid()), txin.get_vout()));
                 }
             }
         }
-        utxo
+        Ok(utxo)
     }
 
-    /// 从区块链中查找交易
-    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+    /// 从区块链中查找交易，建立交易索引后直接通过索引查找
+    pub fn find_transaction(&self, txid: &[u8]) -> crate::Result<Option<Transaction>> {
+        if self.has_txindex()? {
+            let Some((block, position)) = self.find_indexed_transaction(txid)? else {
+                return Ok(None);
+            };
+            return Ok(block.get_transactions().get(position).cloned());
+        }
         let mut iterator = self.iterator();
-        loop {
-            let option = iterator.next();
-            if option.is_none() {
-                break;
-            }
-            let block = option.unwrap();
+        while let Some(block) = iterator.next()? {
             for transaction in block.get_transactions() {
                 if txid.eq(transaction.get_id()) {
-                    return Some(transaction.clone());
+                    return Ok(Some(transaction.clone()));
                 }
             }
         }
-        None
+        Ok(None)
     }
 
-    /// 添加一个区块到区块链
-    pub fn add_block(&self, block: &Block) {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(_) = block_tree.get(block.get_hash()).unwrap() {
-            return;
+    /// 生成交易包含在链中某个区块里的默克尔证明，返回区块哈希和证明
+    pub fn get_tx_out_proof(&self, txid: &[u8]) -> crate::Result<Option<(String, MerkleProof)>> {
+        if self.has_txindex()? {
+            let Some((block, _)) = self.find_indexed_transaction(txid)? else {
+                return Ok(None);
+            };
+            let proof = block.merkle_proof(txid);
+            return Ok(proof.map(|proof| (String::from(block.get_hash()), proof)));
         }
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block.get_hash(), block.serialize()).unwrap();
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next()? {
+            if let Some(proof) = block.merkle_proof(txid) {
+                return Ok(Some((String::from(block.get_hash()), proof)));
+            }
+        }
+        Ok(None)
+    }
 
-            let tip_block_bytes = tx_db
-                .get(self.get_tip_hash())
+    /// 验证默克尔证明与本地区块的默克尔根一致
+    pub fn verify_tx_out_proof(
+        &self,
+        block_hash: &str,
+        proof: &MerkleProof,
+    ) -> crate::Result<bool> {
+        match self.get_block(block_hash.as_bytes())? {
+            Some(block) => {
+                Ok(proof.verify(block.get_merkle_root(), block.get_transactions().len()))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 校验并添加一个区块到区块链
+    /// 如果新区块所在分支的累计工作量超过当前主链，则切换到该分支，返回因链重组而从主链移除的交易
+    pub fn add_block(&self, block: &Block) -> crate::Result<Vec<Transaction>> {
+        let _guard = self.chain_lock.lock().unwrap();
+        self.add_block_locked(block)
+    }
+
+    /// 添加区块，调用者需要持有 chain_lock
+    fn add_block_locked(&self, block: &Block) -> crate::Result<Vec<Transaction>> {
+        if self.get_block(block.get_hash_bytes().as_slice())?.is_some() {
+            return Ok(vec![]);
+        }
+        self.validate_block(block)?;
+        let pre_chain_work = self
+            .get_chain_work(block.get_pre_block_hash().as_str())
+            .ok_or_else(|| Error::InvalidBlock(String::from("previous block is not found")))?;
+        let chain_work = pre_chain_work + block_work(block.get_bits());
+        self.store_block(block, &chain_work)?;
+
+        let tip_chain_work = self
+            .get_chain_work(self.get_tip_hash().as_str())
+            .expect("The tip hash is valid");
+        if chain_work <= tip_chain_work {
+            return Ok(vec![]);
+        }
+        // 新区块直接扩展主链
+        if block.get_pre_block_hash().eq(&self.get_tip_hash()) {
+            let utxo_set = UTXOSet::new(self.clone());
+            self.connect_block(WriteBatch::new(), &utxo_set, block)?;
+            return Ok(vec![]);
+        }
+        self.reorganize(block)
+    }
+
+    /// 链重组：断开旧分支上的区块，再依次校验并连接新分支上的区块
+    fn reorganize(&self, new_tip: &Block) -> crate::Result<Vec<Transaction>> {
+        let old_tip_hash = self.get_tip_hash();
+        let (fork_hash, disconnected, connected) = self.find_fork(new_tip)?;
+        info!(
+            "Reorganize from {} to {}, disconnect {} blocks, connect {} blocks",
+            old_tip_hash,
+            new_tip.get_hash(),
+            disconnected.len(),
+            connected.len()
+        );
+
+        // 断开旧分支，回退到分叉点
+        let utxo_set = UTXOSet::new(self.clone());
+        self.disconnect_blocks(&utxo_set, &disconnected, fork_hash.as_str())?;
+        for (idx, block) in connected.iter().enumerate() {
+            if let Err(e) = self.validate_block(block) {
+                // 新分支无效，移除无效区块及其后代，恢复旧分支
+                for invalid_block in &connected[idx..] {
+                    self.remove_block(invalid_block.get_hash())?;
+                }
+                let mut connected_blocks = connected[..idx].to_vec();
+                connected_blocks.reverse();
+                self.disconnect_blocks(&utxo_set, &connected_blocks, fork_hash.as_str())?;
+                for block in disconnected.iter().rev() {
+                    self.connect_block(WriteBatch::new(), &utxo_set, block)?;
+                }
+                return Err(e);
+            }
+            self.connect_block(WriteBatch::new(), &utxo_set, block)?;
+        }
+
+        // 旧分支中没有被新分支打包的交易需要退回内存池
+        let connected_txids: HashSet<Vec<u8>> = connected
+            .iter()
+            .flat_map(|block| block.get_transactions().iter())
+            .map(|tx| tx.get_id_bytes())
+            .collect();
+        let mut displaced = vec![];
+        for block in disconnected.iter().rev() {
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() && !connected_txids.contains(tx.get_id()) {
+                    displaced.push(tx.clone());
+                }
+            }
+        }
+        Ok(displaced)
+    }
+
+    /// 从最新区块开始依次断开区块，直到回退到 fork_hash
+    fn disconnect_blocks(
+        &self,
+        utxo_set: &UTXOSet,
+        blocks: &[Block],
+        fork_hash: &str,
+    ) -> crate::Result<()> {
+        for block in blocks {
+            match self.disconnect_block(utxo_set, block) {
+                Ok(()) => {}
+                // 缺少撤销数据时切换最新区块，再从主链重建索引和 UTXO 集
+                Err(Error::Corrupted(e)) => {
+                    info!("{}, reindex at {}", e, fork_hash);
+                    self.update_tip(fork_hash)?;
+                    self.rebuild_indexes()?;
+                    utxo_set.reindex()?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// 查找主链与新分支的分叉点
+    /// 返回分叉点区块哈希、需要断开的区块（从最新区块开始）以及需要连接的区块（从分叉点开始）
+    fn find_fork(&self, new_tip: &Block) -> crate::Result<(String, Vec<Block>, Vec<Block>)> {
+        // 只读取区块头查找分叉点
+        let mut old_hash = self.get_tip_hash();
+        let mut old_header = self
+            .get_block_header(old_hash.as_bytes())
+            .expect("The tip hash is valid");
+        let mut new_hash = String::from(new_tip.get_hash());
+        let mut new_header = new_tip.get_header().clone();
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        while old_hash.ne(&new_hash) {
+            if old_header.get_height() >= new_header.get_height() {
+                disconnected.push(old_hash);
+                old_hash = old_header.get_pre_block_hash();
+                old_header = self
+                    .get_block_header(old_hash.as_bytes())
+                    .expect("The previous block is valid");
+            } else {
+                connected.push(new_hash);
+                new_hash = new_header.get_pre_block_hash();
+                new_header = self
+                    .get_block_header(new_hash.as_bytes())
+                    .expect("The previous block is valid");
+            }
+        }
+        connected.reverse();
+        let load_blocks = |hashes: Vec<String>| -> crate::Result<Vec<Block>> {
+            hashes
+                .iter()
+                .map(|hash| {
+                    self.get_block(hash.as_bytes())?
+                        .ok_or_else(|| Error::Corrupted(format!("block {} is not found", hash)))
+                })
+                .collect()
+        };
+        Ok((
+            old_hash,
+            load_blocks(disconnected)?,
+            load_blocks(connected)?,
+        ))
+    }
+
+    /// 保存区块及其累计工作量，不改变最新区块
+    fn store_block(&self, block: &Block, chain_work: &BigInt) -> crate::Result<()> {
+        let batch = Self::block_batch(block, chain_work);
+        self.storage.write_batch(batch)
+    }
+
+    /// 删除区块及其累计工作量
+    fn remove_block(&self, block_hash: &str) -> crate::Result<()> {
+        let mut batch = WriteBatch::new();
+        Self::remove_block_ops(&mut batch, block_hash);
+        self.storage.write_batch(batch)
+    }
+
+    fn remove_block_ops(batch: &mut WriteBatch, block_hash: &str) {
+        batch.remove(BLOCKS_TREE, block_hash);
+        batch.remove(HEADERS_TREE, block_hash);
+        batch.remove(CHAINWORK_TREE, block_hash);
+    }
+
+    /// 更新最新区块，写入成功后才更新内存中的最新区块
+    fn update_tip(&self, block_hash: &str) -> crate::Result<()> {
+        self.storage.insert(
+            BLOCKS_TREE,
+            TIP_BLOCK_HASH_KEY.as_bytes(),
+            block_hash.as_bytes(),
+        )?;
+        self.set_tip_hash(block_hash);
+        Ok(())
+    }
+
+    /// 从最新区块开始沿主链检查区块：能否读取、区块哈希与区块头、高度与难度的衔接、工作量证明及高度索引，
+    /// 返回最早的不一致区块；检查到创世区块且区块都一致时，再将 UTXO 集的哈希与从区块重新计算的结果比较
+    /// check_blocks 为检查的区块数量，0 表示检查整条主链
+    pub fn verify_chain(&self, check_blocks: usize) -> Result<(), ChainInconsistency> {
+        let mut inconsistency = None;
+        let mut block_hash = self.get_tip_hash();
+        let mut height = self
+            .find_header(block_hash.as_str())
+            .map(|header| header.get_height())
+            .unwrap_or_default();
+        let mut child: Option<Block> = None;
+        let mut checked = 0;
+        let mut reached_genesis = false;
+        while check_blocks == 0 || checked < check_blocks {
+            let block = match self
+                .storage
+                .get(BLOCKS_TREE, block_hash.as_bytes())
                 .unwrap()
-                .expect("The tip hash is not valid");
-            let tip_block = Block::deserialize(tip_block_bytes.as_ref());
-            if block.get_height() > tip_block.get_height() {
-                let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash()).unwrap();
-                self.set_tip_hash(block.get_hash());
+            {
+                Some(bytes) => Block::try_deserialize(bytes.as_slice())
+                    .map_err(|e| format!("block is corrupted: {}", e)),
+                None => Err(String::from("block is missing")),
+            };
+            let block = match block {
+                Ok(block) => block,
+                Err(reason) => {
+                    inconsistency = Some(ChainInconsistency::Block {
+                        hash: block_hash,
+                        height,
+                        reason,
+                    });
+                    break;
+                }
+            };
+            // 后一个区块的难度由该区块及之前的区块决定
+            if let Some(child) = &child {
+                let reason = match self.get_next_bits(block.get_header()) {
+                    Ok(expected_bits) if child.get_bits() != expected_bits => Some(format!(
+                        "block bits {:#010x} does not match expected bits {:#010x}",
+                        child.get_bits(),
+                        expected_bits
+                    )),
+                    Ok(_) => None,
+                    Err(e) => Some(format!("unable to compute the expected bits: {}", e)),
+                };
+                if let Some(reason) = reason {
+                    inconsistency = Some(ChainInconsistency::Block {
+                        hash: String::from(child.get_hash()),
+                        height: child.get_height(),
+                        reason,
+                    });
+                }
+            }
+            if let Err(reason) = self.verify_chain_block(block_hash.as_str(), height, &block) {
+                inconsistency = Some(ChainInconsistency::Block {
+                    hash: block_hash,
+                    height,
+                    reason,
+                });
+            }
+            if height == 0 {
+                reached_genesis = true;
+                break;
+            }
+            block_hash = block.get_pre_block_hash();
+            height -= 1;
+            child = Some(block);
+            checked += 1;
+        }
+        if let Some(inconsistency) = inconsistency {
+            return Err(inconsistency);
+        }
+        // 重新计算 UTXO 集需要读取所有区块，只有所有区块都检查过才可靠
+        if !reached_genesis {
+            return Ok(());
+        }
+
+        let utxo_set = UTXOSet::new(self.clone());
+        let hash = utxo_set.get_hash();
+        // 前面已经读取并检查了主链中的所有区块
+        let computed_hash = utxo_set
+            .compute_hash()
+            .expect("The blocks of the main chain are readable");
+        if hash.ne(&computed_hash) {
+            return Err(ChainInconsistency::UTXOSet {
+                hash: HEXLOWER.encode(hash.as_slice()),
+                computed_hash: HEXLOWER.encode(computed_hash.as_slice()),
+            });
+        }
+        Ok(())
+    }
+
+    /// 检查主链中高度为 height 的区块本身及其区块头、高度索引
+    fn verify_chain_block(
+        &self,
+        block_hash: &str,
+        height: usize,
+        block: &Block,
+    ) -> Result<(), String> {
+        if block.get_hash().ne(block_hash) {
+            return Err(format!("block is stored under another hash {}", block_hash));
+        }
+        if block.get_height() != height {
+            return Err(format!(
+                "block height {} does not follow the next block height {}",
+                block.get_height(),
+                height + 1
+            ));
+        }
+        // 默克尔根、交易及工作量证明
+        block.validate().map_err(|e| e.to_string())?;
+        let header_bytes = self
+            .storage
+            .get(HEADERS_TREE, block_hash.as_bytes())
+            .unwrap()
+            .ok_or_else(|| String::from("block header is missing"))?;
+        if header_bytes.ne(&block.get_header().serialize()) {
+            return Err(String::from("block header does not match the block"));
+        }
+        if self.get_block_hash(height).as_deref() != Some(block_hash) {
+            return Err(String::from("height index does not point to the block"));
+        }
+        Ok(())
+    }
+
+    /// 回滚主链，使 block_hash 对应的区块及其后代不再属于主链，并删除这些区块以便重新下载
+    /// 被删除区块的撤销数据不再可信，因此从区块重建 UTXO 集及各个索引，返回删除的区块数量
+    pub fn rollback_to(&self, block_hash: &str) -> crate::Result<usize> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let target_height = self
+            .find_header(block_hash)
+            .ok_or_else(|| Error::Corrupted(format!("block {} is not found", block_hash)))?
+            .get_height();
+        if target_height == 0 {
+            return Err(Error::Corrupted(String::from(
+                "the genesis block cannot be rolled back",
+            )));
+        }
+        // 从最新区块开始沿主链找到需要删除的区块
+        let mut batch = WriteBatch::new();
+        let mut count = 0;
+        let mut hash = self.get_tip_hash();
+        let parent_hash = loop {
+            let header = self
+                .find_header(hash.as_str())
+                .ok_or_else(|| Error::Corrupted(format!("block {} is not found", hash)))?;
+            if header.get_height() < target_height {
+                return Err(Error::Corrupted(format!(
+                    "block {} is not in the main chain",
+                    block_hash
+                )));
             }
-            Ok(())
-        });
+            Self::remove_block_ops(&mut batch, hash.as_str());
+            count += 1;
+            if hash.eq(block_hash) {
+                break header.get_pre_block_hash();
+            }
+            hash = header.get_pre_block_hash();
+        };
+        if self.get_chain_work(parent_hash.as_str()).is_none() {
+            return Err(Error::Corrupted(format!(
+                "parent block {} is not found",
+                parent_hash
+            )));
+        }
+        batch.insert(BLOCKS_TREE, TIP_BLOCK_HASH_KEY, parent_hash.as_str());
+        self.storage.write_batch(batch)?;
+        self.set_tip_hash(parent_hash.as_str());
+        info!("Rolled back {} blocks to {}", count, parent_hash);
+
+        self.rebuild_indexes()?;
+        UTXOSet::new(self.clone()).reindex()?;
+        Ok(count)
+    }
+
+    /// 查询区块头，区块头缺失或损坏时从区块中读取
+    fn find_header(&self, block_hash: &str) -> Option<BlockHeader> {
+        let header = self
+            .storage
+            .get(HEADERS_TREE, block_hash.as_bytes())
+            .unwrap()
+            .and_then(|bytes| bincode::deserialize(bytes.as_slice()).ok());
+        if header.is_some() {
+            return header;
+        }
+        let block_bytes = self
+            .storage
+            .get(BLOCKS_TREE, block_hash.as_bytes())
+            .unwrap()?;
+        let block = Block::try_deserialize(block_bytes.as_slice()).ok()?;
+        Some(block.get_header().clone())
+    }
+
+    /// 获取区块所在分支的累计工作量
+    pub fn get_chain_work(&self, block_hash: &str) -> Option<BigInt> {
+        let work_bytes = self
+            .storage
+            .get(CHAINWORK_TREE, block_hash.as_bytes())
+            .unwrap()?;
+        Some(BigInt::from_signed_bytes_be(work_bytes.as_slice()))
+    }
+
+    /// 校验来自网络的区块，只有扩展当前最新区块时才会校验 UTXO 相关规则
+    pub fn validate_block(&self, block: &Block) -> crate::Result<()> {
+        block.validate()?;
+        let pre_block = self
+            .get_block_header(block.get_pre_block_hash().as_bytes())
+            .ok_or_else(|| Error::InvalidBlock(String::from("previous block is not found")))?;
+        if block.get_height() != pre_block.get_height() + 1 {
+            return Err(Error::InvalidBlock(format!(
+                "block height {} does not follow previous block height {}",
+                block.get_height(),
+                pre_block.get_height()
+            )));
+        }
+        let expected_bits = self.get_next_bits(&pre_block)?;
+        if block.get_bits() != expected_bits {
+            return Err(Error::InvalidBlock(format!(
+                "block bits {:#010x} does not match expected bits {:#010x}",
+                block.get_bits(),
+                expected_bits
+            )));
+        }
+        // 难度调整依赖区块时间戳，时间戳不能早于之前区块的中位时间，也不能超前本地时间太多
+        let median_time_past = self.get_median_time_past(&pre_block);
+        if block.get_timestamp() <= median_time_past {
+            return Err(Error::InvalidBlock(format!(
+                "block timestamp {} is not after the median time past {}",
+                block.get_timestamp(),
+                median_time_past
+            )));
+        }
+        if block.get_timestamp() > crate::current_timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(Error::InvalidBlock(format!(
+                "block timestamp {} is too far in the future",
+                block.get_timestamp()
+            )));
+        }
+        if block.get_pre_block_hash().ne(&self.get_tip_hash()) {
+            return Ok(());
+        }
+
+        let utxo_set = UTXOSet::new(self.clone());
+        let mut spent_outputs: HashSet<(Vec<u8>, usize)> = HashSet::new();
+        // 区块中已经校验过的交易，后面的交易可以花费它们的输出
+        let mut block_txs: HashMap<Vec<u8>, &Transaction> = HashMap::new();
+        // 来自网络的金额可能溢出，溢出的区块无效
+        let overflow = || Error::InvalidBlock(String::from("transaction values overflow"));
+        let mut coinbase_value: i32 = 0;
+        let mut fees: i32 = 0;
+        for tx in block.get_transactions() {
+            if tx.is_coinbase() {
+                coinbase_value = tx
+                    .get_output_value()
+                    .and_then(|value| coinbase_value.checked_add(value))
+                    .ok_or_else(overflow)?;
+                block_txs.insert(tx.get_id_bytes(), tx);
+                continue;
+            }
+            let mut input_value: i32 = 0;
+            let mut prev_outputs = vec![];
+            for vin in tx.get_vin() {
+                // 同一区块内不允许重复花费同一个输出
+                if !spent_outputs.insert((vin.get_txid().to_vec(), vin.get_vout())) {
+                    return Err(Error::InvalidBlock(String::from(
+                        "double spend inside the block",
+                    )));
+                }
+                let entry = match block_txs.get(vin.get_txid()) {
+                    Some(prev_tx) => prev_tx.get_vout().get(vin.get_vout()).map(|out| {
+                        UTXOEntry::new(out.clone(), block.get_height(), prev_tx.is_coinbase())
+                    }),
+                    None => utxo_set.find_entry(vin.get_txid(), vin.get_vout()),
+                }
+                .ok_or_else(|| {
+                    Error::InvalidBlock(String::from("input is already spent or does not exist"))
+                })?;
+                let output = entry.get_output();
+                if !entry.is_mature(block.get_height()) {
+                    return Err(Error::InvalidBlock(String::from(
+                        "input spends an immature coinbase output",
+                    )));
+                }
+                if !output.is_locked_with_key(hash_pub_key(vin.get_pub_key()).as_slice()) {
+                    return Err(Error::InvalidBlock(String::from(
+                        "input is not owned by its public key",
+                    )));
+                }
+                input_value = input_value
+                    .checked_add(output.get_value())
+                    .ok_or_else(overflow)?;
+                prev_outputs.push(output.clone());
+            }
+            let output_value = tx.get_output_value().ok_or_else(overflow)?;
+            if output_value > input_value {
+                return Err(Error::InvalidBlock(String::from(
+                    "transaction spends more than its inputs",
+                )));
+            }
+            fees = fees
+                .checked_add(input_value - output_value)
+                .ok_or_else(overflow)?;
+            if !tx.verify_with_outputs(prev_outputs.as_slice()) {
+                return Err(Error::InvalidBlock(String::from(
+                    "transaction signature is not valid",
+                )));
+            }
+            block_txs.insert(tx.get_id_bytes(), tx);
+        }
+        // 矿工最多获得区块高度对应的挖矿奖励加上区块中所有交易的手续费
+        let subsidy = get_block_subsidy(block.get_height());
+        if coinbase_value as i64 > subsidy as i64 + fees as i64 {
+            return Err(Error::InvalidBlock(format!(
+                "coinbase pays {} which exceeds the subsidy {} plus fees {}",
+                coinbase_value, subsidy, fees
+            )));
+        }
+        Ok(())
+    }
+
+    /// 计算下一个区块（即 pre_header 的子区块）应使用的难度
+    /// 每隔 DIFFICULTY_ADJUSTMENT_INTERVAL 个区块，根据上一个周期的出块时间重新计算难度，其余区块沿用父区块的难度
+    pub fn get_next_bits(&self, pre_header: &BlockHeader) -> crate::Result<u32> {
+        let height = pre_header.get_height() + 1;
+        if !height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+            return Ok(pre_header.get_bits());
+        }
+        // 回溯到上一个周期的第一个区块
+        let mut first_header = pre_header.clone();
+        for _ in 0..DIFFICULTY_ADJUSTMENT_INTERVAL - 1 {
+            let pre_hash = first_header.get_pre_block_hash();
+            first_header = self
+                .find_header(pre_hash.as_str())
+                .ok_or_else(|| Error::Corrupted(format!("block header {} is missing", pre_hash)))?;
+        }
+        let actual_timespan = pre_header.get_timestamp() - first_header.get_timestamp();
+        Ok(retarget_bits(pre_header.get_bits(), actual_timespan))
+    }
+
+    /// 计算 header 及其之前共 MEDIAN_TIME_SPAN 个区块时间戳的中位数
+    pub fn get_median_time_past(&self, header: &BlockHeader) -> i64 {
+        let mut timestamps = vec![header.get_timestamp()];
+        let mut pre_hash = header.get_pre_block_hash();
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.get_block_header(pre_hash.as_bytes()) {
+                Some(pre_header) => {
+                    timestamps.push(pre_header.get_timestamp());
+                    pre_hash = pre_header.get_pre_block_hash();
+                }
+                None => break,
+            }
+        }
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// 计算 pre_header 的子区块可以使用的时间戳：本地时间，但至少比中位时间大 1
+    pub fn get_next_timestamp(&self, pre_header: &BlockHeader) -> i64 {
+        crate::current_timestamp().max(self.get_median_time_past(pre_header) + 1)
     }
 
     /// 获取最新区块在链中的高度
     pub fn get_best_height(&self) -> usize {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let tip_block_bytes = block_tree
-            .get(self.get_tip_hash())
-            .unwrap()
+        let tip_header = self
+            .find_header(self.get_tip_hash().as_str())
             .expect("The tip hash is valid");
-        let tip_block = Block::deserialize(tip_block_bytes.as_ref());
-        tip_block.get_height()
+        tip_header.get_height()
+    }
+
+    /// 通过区块哈希查询区块头
+    pub fn get_block_header(&self, block_hash: &[u8]) -> Option<BlockHeader> {
+        let header_bytes = self.storage.get(HEADERS_TREE, block_hash).unwrap()?;
+        bincode::deserialize(header_bytes.as_slice()).ok()
     }
 
     /// 通过区块哈希查询区块
-    pub fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(block_bytes) = block_tree.get(block_hash).unwrap() {
-            let block = Block::deserialize(block_bytes.as_ref());
-            return Some(block);
+    pub fn get_block(&self, block_hash: &[u8]) -> crate::Result<Option<Block>> {
+        match self.storage.get(BLOCKS_TREE, block_hash)? {
+            Some(block_bytes) => Ok(Some(Block::try_deserialize(block_bytes.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 查询主链中指定高度的区块哈希
+    pub fn get_block_hash(&self, height: usize) -> Option<String> {
+        let hash_bytes = self
+            .storage
+            .get(HEIGHTS_TREE, height_key(height).as_slice())
+            .unwrap()?;
+        Some(String::from_utf8(hash_bytes).unwrap())
+    }
+
+    /// 查询主链中指定高度的区块
+    pub fn get_block_by_height(&self, height: usize) -> crate::Result<Option<Block>> {
+        match self.get_block_hash(height) {
+            Some(block_hash) => self.get_block(block_hash.as_bytes()),
+            None => Ok(None),
+        }
+    }
+
+    /// 从 start_height 开始按高度升序遍历主链区块，直到 end_height（包含）或最新区块
+    pub fn range_iterator(&self, start_height: usize, end_height: usize) -> BlockRangeIterator {
+        BlockRangeIterator {
+            blockchain: self.clone(),
+            next_height: start_height,
+            end_height,
         }
-        return None;
     }
 
     /// 返回链中所有区块的哈希列表
     pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
-        let mut iterator = self.iterator();
+        let mut iterator = self.header_iterator();
         let mut blocks = vec![];
         loop {
             let option = iterator.next();
             if option.is_none() {
                 break;
             }
-            let block = option.unwrap();
-            blocks.push(block.get_hash_bytes());
+            let (block_hash, _) = option.unwrap();
+            blocks.push(block_hash.into_bytes());
         }
         return blocks;
     }
 }
 
+/// 区块链完整性检查发现的不一致
+#[derive(Debug)]
+pub enum ChainInconsistency {
+    /// 主链中最早的不一致区块
+    Block {
+        hash: String,
+        height: usize,
+        reason: String,
+    },
+    /// 区块都一致，但 UTXO 集与从区块重新计算的结果不同
+    UTXOSet { hash: String, computed_hash: String },
+}
+
+impl fmt::Display for ChainInconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainInconsistency::Block {
+                hash,
+                height,
+                reason,
+            } => write!(f, "block {} at height {}: {}", hash, height, reason),
+            ChainInconsistency::UTXOSet {
+                hash,
+                computed_hash,
+            } => write!(
+                f,
+                "UTXO set hash {} does not match the recomputed hash {}",
+                hash, computed_hash
+            ),
+        }
+    }
+}
+
+/// 地址交易历史中的一笔交易
+pub struct AddressHistoryEntry {
+    txid: Vec<u8>,
+    height: usize,
+    received: i32, // 交易支付给该地址的金额
+    spent: i32,    // 交易花费的该地址的金额
+    balance: i32,  // 交易之后地址的余额
+}
+
+impl AddressHistoryEntry {
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_received(&self) -> i32 {
+        self.received
+    }
+
+    pub fn get_spent(&self) -> i32 {
+        self.spent
+    }
+
+    pub fn get_balance(&self) -> i32 {
+        self.balance
+    }
+}
+
+/// 高度索引的键，大端序保证按键遍历时高度递增
+fn height_key(height: usize) -> [u8; 8] {
+    (height as u64).to_be_bytes()
+}
+
+/// 地址索引的键，公钥哈希长度固定，按公钥哈希前缀查询时记录按区块高度和交易位置排序
+fn address_index_key(pub_key_hash: &[u8], height: usize, position: usize) -> Vec<u8> {
+    [
+        pub_key_hash,
+        height_key(height).as_slice(),
+        (position as u32).to_be_bytes().as_slice(),
+    ]
+    .concat()
+}
+
+/// 从地址索引键去掉公钥哈希后的部分解析出区块高度和交易位置
+fn parse_address_index_key(bytes: &[u8]) -> (usize, usize) {
+    let (height, position) = bytes.split_at(8);
+    (
+        u64::from_be_bytes(height.try_into().unwrap()) as usize,
+        u32::from_be_bytes(position.try_into().unwrap()) as usize,
+    )
+}
+
+/// 按高度升序遍历主链区块的迭代器，每次只加载一个区块
+pub struct BlockRangeIterator {
+    blockchain: Blockchain,
+    next_height: usize,
+    end_height: usize,
+}
+
+impl Iterator for BlockRangeIterator {
+    type Item = crate::Result<Block>;
+
+    fn next(&mut self) -> Option<crate::Result<Block>> {
+        if self.next_height > self.end_height {
+            return None;
+        }
+        let block = self
+            .blockchain
+            .get_block_by_height(self.next_height)
+            .transpose()?;
+        self.next_height += 1;
+        Some(block)
+    }
+}
+
 pub struct BlockchainIterator {
-    db: Db,
+    storage: Arc<dyn Storage>,
     current_hash: String,
 }
 
 impl BlockchainIterator {
-    fn new(tip_hash: String, db: Db) -> BlockchainIterator {
+    fn new(tip_hash: String, storage: Arc<dyn Storage>) -> BlockchainIterator {
         BlockchainIterator {
             current_hash: tip_hash,
-            db,
+            storage,
         }
     }
 
-    pub fn next(&mut self) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let data = block_tree.get(self.current_hash.clone()).unwrap();
-        if data.is_none() {
-            return None;
-        }
-        let block = Block::deserialize(data.unwrap().to_vec().as_slice());
+    pub fn next(&mut self) -> crate::Result<Option<Block>> {
+        let Some(data) = self
+            .storage
+            .get(BLOCKS_TREE, self.current_hash.as_bytes())?
+        else {
+            return Ok(None);
+        };
+        let block = Block::try_deserialize(data.as_slice())?;
         self.current_hash = block.get_pre_block_hash().clone();
-        return Some(block);
+        Ok(Some(block))
+    }
+}
+
+/// 区块头迭代器，遍历链时不需要读取交易数据
+pub struct BlockHeaderIterator {
+    storage: Arc<dyn Storage>,
+    current_hash: String,
+}
+
+impl BlockHeaderIterator {
+    fn new(tip_hash: String, storage: Arc<dyn Storage>) -> BlockHeaderIterator {
+        BlockHeaderIterator {
+            current_hash: tip_hash,
+            storage,
+        }
+    }
+
+    /// 返回区块哈希及区块头
+    pub fn next(&mut self) -> Option<(String, BlockHeader)> {
+        let data = self
+            .storage
+            .get(HEADERS_TREE, self.current_hash.as_bytes())
+            .unwrap()?;
+        let header: BlockHeader = bincode::deserialize(data.as_slice()).ok()?;
+        let block_hash = std::mem::replace(&mut self.current_hash, header.get_pre_block_hash());
+        Some((block_hash, header))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Block;
+    use super::{height_key, BLOCKS_TREE, HEADERS_TREE, HEIGHTS_TREE};
+    use crate::utxo_set::UNDO_TREE;
+    use crate::{
+        decode_address, get_block_subsidy, Block, ChainInconsistency, Error, MemoryStorage,
+        Storage, Transaction, UTXOSet,
+    };
+    use std::sync::Arc;
+
+    const GENESIS_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn new_memory_blockchain() -> super::Blockchain {
+        super::Blockchain::create_with_storage(Arc::new(MemoryStorage::new()), GENESIS_ADDRESS)
+            .unwrap()
+    }
+
+    /// 从 from_hash 开始挖出 count 个区块组成的分支，每个区块只包含支付给 address 的 coinbase 交易
+    fn mine_fork(
+        blockchain: &super::Blockchain,
+        from_hash: &str,
+        count: usize,
+        address: &str,
+    ) -> Vec<Block> {
+        let mut pre_hash = String::from(from_hash);
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let pre_header = blockchain.get_block_header(pre_hash.as_bytes()).unwrap();
+            let height = pre_header.get_height() + 1;
+            let block = Block::new_block_at(
+                pre_hash,
+                &[Transaction::new_coinbase_tx(address, height, 0)],
+                height,
+                blockchain.get_next_bits(&pre_header).unwrap(),
+                blockchain.get_next_timestamp(&pre_header),
+            );
+            blockchain.add_block(&block).unwrap();
+            pre_hash = String::from(block.get_hash());
+            blocks.push(block);
+        }
+        blocks
+    }
 
     #[test]
     fn test_create_blockchain() {
-        let _ = super::Blockchain::create_blockchain("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let storage = Arc::new(MemoryStorage::new());
+        assert!(matches!(
+            super::Blockchain::open_with_storage(storage.clone()),
+            Err(Error::BlockchainNotFound)
+        ));
+        let blockchain =
+            super::Blockchain::create_with_storage(storage.clone(), GENESIS_ADDRESS).unwrap();
+        // 区块链已存在时直接打开
+        let opened = super::Blockchain::create_with_storage(storage, GENESIS_ADDRESS).unwrap();
+        assert_eq!(opened.get_tip_hash(), blockchain.get_tip_hash());
+
+        // 旧版本创建的数据库只有区块和最新区块哈希
+        let storage = Arc::new(MemoryStorage::new());
+        storage
+            .insert(BLOCKS_TREE, super::TIP_BLOCK_HASH_KEY.as_bytes(), b"00ab")
+            .unwrap();
+        assert!(matches!(
+            super::Blockchain::open_with_storage(storage),
+            Err(Error::IncompatibleDatabase)
+        ));
+    }
+
+    #[test]
+    fn test_open_data_dir() {
+        let data_dir = crate::temp_data_dir();
+        assert!(matches!(
+            super::Blockchain::open(&data_dir),
+            Err(Error::BlockchainNotFound)
+        ));
+
+        let tip_hash = {
+            let blockchain = super::Blockchain::create(&data_dir, GENESIS_ADDRESS).unwrap();
+            blockchain.get_tip_hash()
+        };
+        // 数据库关闭后重新打开同一个数据目录，sled 的后台线程可能稍晚才释放文件锁
+        let mut retries = 0;
+        let blockchain = loop {
+            match super::Blockchain::open(&data_dir) {
+                Err(Error::Storage(_)) if retries < 50 => {
+                    retries += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                result => break result.unwrap(),
+            }
+        };
+        assert_eq!(blockchain.get_tip_hash(), tip_hash);
+        assert_eq!(blockchain.get_best_height(), 0);
+
+        drop(blockchain);
+        std::fs::remove_dir_all(&data_dir).unwrap();
     }
 
     #[test]
     fn test_mine_block() {
-        let blockchain = super::Blockchain::new_blockchain();
-        let _ = blockchain.mine_block(&vec![]);
+        let blockchain = new_memory_blockchain();
+        let block = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+    }
+
+    #[test]
+    fn test_mine_block_concurrently() {
+        // 多个线程同时挖矿，最新区块改变时新区块被丢弃，不会破坏主链
+        let blockchain = new_memory_blockchain();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let blockchain = blockchain.clone();
+                std::thread::spawn(move || {
+                    let mut mined = 0;
+                    for _ in 0..3 {
+                        let height = blockchain.get_best_height() + 1;
+                        let coinbase_tx = Transaction::new_coinbase_tx(GENESIS_ADDRESS, height, 0);
+                        match blockchain.mine_block(&[coinbase_tx]) {
+                            Ok(_) => mined += 1,
+                            Err(Error::TipChanged) => {}
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                    mined
+                })
+            })
+            .collect();
+        let mined: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(blockchain.get_best_height(), mined);
+        assert!(blockchain.verify_chain(0).is_ok());
     }
 
     #[test]
     fn test_get_best_height() {
-        let blockchain = super::Blockchain::new_blockchain();
-        println!(
-            "tip_hash = {}, best_height: {}",
-            blockchain.get_tip_hash(),
-            blockchain.get_best_height()
-        );
+        let blockchain = new_memory_blockchain();
+        assert_eq!(blockchain.get_best_height(), 0);
+        blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        assert_eq!(blockchain.get_best_height(), 1);
     }
 
     #[test]
     fn test_add_block() {
-        let blockchain = super::Blockchain::new_blockchain();
-        let best_height = blockchain.get_best_height();
-        let block = Block::new_block(blockchain.get_tip_hash(), &vec![], best_height + 1);
-        blockchain.add_block(&block);
-        println!(
-            "tip_hash = {}, best_height = {}",
-            blockchain.get_tip_hash(),
-            blockchain.get_best_height()
-        );
+        let blockchain = new_memory_blockchain();
+        let tip_block = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap()
+            .unwrap();
+        let new_block = |transactions: &[Transaction]| {
+            Block::new_block_at(
+                blockchain.get_tip_hash(),
+                transactions,
+                tip_block.get_height() + 1,
+                blockchain.get_next_bits(tip_block.get_header()).unwrap(),
+                blockchain.get_next_timestamp(tip_block.get_header()),
+            )
+        };
+        // 无效区块不会被加入区块链
+        let invalid_block = new_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 100)]);
+        assert!(blockchain.add_block(&invalid_block).is_err());
+        assert!(blockchain
+            .get_block(invalid_block.get_hash_bytes().as_slice())
+            .unwrap()
+            .is_none());
+
+        let block = new_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)]);
+        blockchain.add_block(&block).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert_eq!(blockchain.get_best_height(), 1);
+    }
+
+    #[test]
+    fn test_block_timestamp() {
+        let blockchain = new_memory_blockchain();
+        let block = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        let median_time_past = blockchain.get_median_time_past(block.get_header());
+        let new_block = |timestamp| {
+            Block::new_block_at(
+                String::from(block.get_hash()),
+                &[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 2, 0)],
+                2,
+                blockchain.get_next_bits(block.get_header()).unwrap(),
+                timestamp,
+            )
+        };
+        // 时间戳不能早于中位时间，也不能超前本地时间太多
+        assert!(blockchain
+            .validate_block(&new_block(median_time_past))
+            .is_err());
+        let future = crate::current_timestamp() + super::MAX_FUTURE_BLOCK_TIME + 60 * 1000;
+        assert!(blockchain.validate_block(&new_block(future)).is_err());
+        let timestamp = blockchain.get_next_timestamp(block.get_header());
+        assert!(blockchain.validate_block(&new_block(timestamp)).is_ok());
     }
 
     #[test]
     fn test_get_block_hashes() {
-        let blockchain = super::Blockchain::new_blockchain();
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let block = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
         let block_hashs = blockchain.get_block_hashes();
-        for hash_bytes in block_hashs {
-            println!("{}", String::from_utf8(hash_bytes).unwrap())
-        }
+        assert_eq!(
+            block_hashs,
+            vec![block.get_hash_bytes(), genesis_hash.into_bytes()]
+        );
     }
 
     #[test]
     fn test_get_block() {
-        let blockchain = super::Blockchain::new_blockchain();
-        if let Some(block) = blockchain.get_block(
-            "0060a9e030158c9fa012f06eeb18f8d1f26523aa1483face260730c14a140fce".as_bytes(),
-        ) {
-            println!("{}", block.get_hash())
+        let blockchain = new_memory_blockchain();
+        let block = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.get_height(), 0);
+        assert!(blockchain
+            .get_block(
+                "0060a9e030158c9fa012f06eeb18f8d1f26523aa1483face260730c14a140fce".as_bytes(),
+            )
+            .unwrap()
+            .is_none());
+
+        // 数据库中损坏的区块返回错误
+        let tip_hash = blockchain.get_tip_hash();
+        blockchain
+            .get_storage()
+            .insert(BLOCKS_TREE, tip_hash.as_bytes(), b"corrupt")
+            .unwrap();
+        assert!(blockchain.get_block(tip_hash.as_bytes()).is_err());
+        assert!(blockchain.iterator().next().is_err());
+    }
+
+    #[test]
+    fn test_txindex() {
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let coinbase_tx = Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0);
+        let block = blockchain.mine_block(&[coinbase_tx.clone()]).unwrap();
+        assert!(!blockchain.has_txindex().unwrap());
+        assert_eq!(blockchain.reindex_transactions().unwrap(), 2);
+        assert!(blockchain.has_txindex().unwrap());
+        assert!(blockchain
+            .find_transaction(coinbase_tx.get_id())
+            .unwrap()
+            .is_some());
+        let (block_hash, _) = blockchain
+            .get_tx_out_proof(coinbase_tx.get_id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(block_hash, block.get_hash());
+
+        // 新挖出的区块同步加入索引
+        let next_tx = Transaction::new_coinbase_tx(GENESIS_ADDRESS, 2, 0);
+        blockchain.mine_block(&[next_tx.clone()]).unwrap();
+        assert!(blockchain
+            .find_transaction(next_tx.get_id())
+            .unwrap()
+            .is_some());
+
+        // 链重组后，被断开区块中的交易从索引中移除
+        let fork = mine_fork(&blockchain, genesis_hash.as_str(), 3, GENESIS_ADDRESS);
+        assert_eq!(blockchain.get_tip_hash(), fork[2].get_hash());
+        assert!(blockchain
+            .find_transaction(coinbase_tx.get_id())
+            .unwrap()
+            .is_none());
+        assert!(blockchain
+            .find_transaction(next_tx.get_id())
+            .unwrap()
+            .is_none());
+        for block in &fork {
+            let tx = &block.get_transactions()[0];
+            assert!(blockchain.find_transaction(tx.get_id()).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_utxo_set_follows_tip() {
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        assert_eq!(
+            utxo_set.get_total_amount(),
+            (get_block_subsidy(0) + get_block_subsidy(1)) as i64
+        );
+
+        // 链重组时逐个断开和连接区块，UTXO 集始终与最新区块一致
+        let other_address = "1LecNaLYsDoxRtxBBWKMNbLvccftmFZWcv";
+        let fork = mine_fork(&blockchain, genesis_hash.as_str(), 3, other_address);
+        assert_eq!(blockchain.get_tip_hash(), fork[2].get_hash());
+        let pub_key_hash = decode_address(GENESIS_ADDRESS).unwrap();
+        let other_pub_key_hash = decode_address(other_address).unwrap();
+        assert_eq!(utxo_set.find_utxo(pub_key_hash.as_slice()).len(), 1);
+        assert_eq!(utxo_set.find_utxo(other_pub_key_hash.as_slice()).len(), 3);
+        let total_amount = utxo_set.get_total_amount();
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.get_total_amount(), total_amount);
+        assert_eq!(utxo_set.count_outputs(), 4);
+    }
+
+    #[test]
+    fn test_reorg_without_undo_data() {
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let block1 = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 2, 0)])
+            .unwrap();
+        // 第 2 个区块可以正常断开，第 1 个区块缺少撤销数据，只能从分叉点重建索引和 UTXO 集
+        blockchain
+            .get_storage()
+            .remove(UNDO_TREE, block1.get_hash().as_bytes())
+            .unwrap();
+
+        let other_address = "1LecNaLYsDoxRtxBBWKMNbLvccftmFZWcv";
+        let fork = mine_fork(&blockchain, genesis_hash.as_str(), 3, other_address);
+        assert_eq!(blockchain.get_tip_hash(), fork[2].get_hash());
+        for (idx, block) in fork.iter().enumerate() {
+            assert_eq!(
+                blockchain.get_block_hash(idx + 1).unwrap(),
+                block.get_hash()
+            );
+        }
+        assert!(blockchain.get_block_hash(4).is_none());
+        let pub_key_hash = decode_address(GENESIS_ADDRESS).unwrap();
+        let other_pub_key_hash = decode_address(other_address).unwrap();
+        assert_eq!(utxo_set.find_utxo(pub_key_hash.as_slice()).len(), 1);
+        assert_eq!(utxo_set.find_utxo(other_pub_key_hash.as_slice()).len(), 3);
+        assert_eq!(utxo_set.count_outputs(), 4);
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let block1 = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        let block2 = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 2, 0)])
+            .unwrap();
+        assert!(blockchain.verify_chain(0).is_ok());
+
+        // UTXO 集与区块不一致
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.rollback(&block2).unwrap();
+        assert!(matches!(
+            blockchain.verify_chain(0),
+            Err(ChainInconsistency::UTXOSet { .. })
+        ));
+        utxo_set.reindex().unwrap();
+        assert!(blockchain.verify_chain(0).is_ok());
+
+        // 区块数据损坏时报告最早的不一致区块，回滚后主链回到其父区块
+        blockchain
+            .storage
+            .insert(BLOCKS_TREE, block1.get_hash().as_bytes(), b"corrupted")
+            .unwrap();
+        assert!(blockchain.verify_chain(1).is_ok());
+        match blockchain.verify_chain(0) {
+            Err(ChainInconsistency::Block { hash, height, .. }) => {
+                assert_eq!(hash, block1.get_hash());
+                assert_eq!(height, 1);
+            }
+            _ => panic!("the corrupted block is not found"),
+        }
+        assert_eq!(blockchain.rollback_to(block1.get_hash()).unwrap(), 2);
+        assert_eq!(blockchain.get_tip_hash(), genesis_hash);
+        assert_eq!(blockchain.get_best_height(), 0);
+        assert_eq!(utxo_set.get_total_amount(), get_block_subsidy(0) as i64);
+        assert!(blockchain.verify_chain(0).is_ok());
+    }
+
+    #[test]
+    fn test_verify_chain_missing_headers() {
+        let storage = Arc::new(MemoryStorage::new());
+        let blockchain =
+            super::Blockchain::create_with_storage(storage.clone(), GENESIS_ADDRESS).unwrap();
+        let mut blocks = vec![];
+        for height in 1..=10 {
+            let coinbase_tx = Transaction::new_coinbase_tx(GENESIS_ADDRESS, height, 0);
+            blocks.push(blockchain.mine_block(&[coinbase_tx]).unwrap());
+        }
+
+        // 计算调整后的难度时缺少祖先区块，报告为不一致而不是 panic
+        let missing_block = &blocks[2];
+        storage
+            .remove(HEADERS_TREE, missing_block.get_hash().as_bytes())
+            .unwrap();
+        storage
+            .remove(BLOCKS_TREE, missing_block.get_hash().as_bytes())
+            .unwrap();
+        match blockchain.verify_chain(2) {
+            Err(ChainInconsistency::Block { height, .. }) => assert_eq!(height, 10),
+            _ => panic!("the missing ancestor is not reported"),
+        }
+        match blockchain.verify_chain(0) {
+            Err(ChainInconsistency::Block { hash, .. }) => {
+                assert_eq!(hash, missing_block.get_hash())
+            }
+            _ => panic!("the missing block is not found"),
         }
+
+        // 最新区块损坏时仍然可以打开区块链并报告不一致
+        let tip_hash = blockchain.get_tip_hash();
+        storage
+            .insert(HEADERS_TREE, tip_hash.as_bytes(), b"corrupted")
+            .unwrap();
+        storage
+            .insert(BLOCKS_TREE, tip_hash.as_bytes(), b"corrupted")
+            .unwrap();
+        let blockchain = super::Blockchain::open_with_storage(storage).unwrap();
+        match blockchain.verify_chain(1) {
+            Err(ChainInconsistency::Block { hash, .. }) => assert_eq!(hash, tip_hash),
+            _ => panic!("the corrupted tip is not found"),
+        }
+    }
+
+    #[test]
+    fn test_address_index() {
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let pub_key_hash = decode_address(GENESIS_ADDRESS).unwrap();
+        assert!(!blockchain.has_addrindex().unwrap());
+        assert_eq!(blockchain.reindex_addresses().unwrap(), 1);
+        assert!(blockchain.has_addrindex().unwrap());
+
+        // 新挖出的区块同步加入索引，余额按交易顺序累计
+        let coinbase_tx = Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0);
+        blockchain.mine_block(&[coinbase_tx.clone()]).unwrap();
+        let history = blockchain
+            .get_address_history(pub_key_hash.as_slice())
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].get_txid(), coinbase_tx.get_id());
+        assert_eq!(history[1].get_height(), 1);
+        assert_eq!(history[1].get_received(), get_block_subsidy(1));
+        assert_eq!(history[1].get_spent(), 0);
+        assert_eq!(
+            history[1].get_balance(),
+            get_block_subsidy(0) + get_block_subsidy(1)
+        );
+
+        // 链重组后，被断开区块中的交易从索引中移除
+        let other_address = "1LecNaLYsDoxRtxBBWKMNbLvccftmFZWcv";
+        let fork = mine_fork(&blockchain, genesis_hash.as_str(), 2, other_address);
+        assert_eq!(blockchain.get_tip_hash(), fork[1].get_hash());
+        let history = blockchain
+            .get_address_history(pub_key_hash.as_slice())
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].get_height(), 0);
+        let other_pub_key_hash = decode_address(other_address).unwrap();
+        let other_history = blockchain
+            .get_address_history(other_pub_key_hash.as_slice())
+            .unwrap();
+        assert_eq!(other_history.len(), 2);
+        assert_eq!(
+            other_history[1].get_balance(),
+            get_block_subsidy(1) + get_block_subsidy(2)
+        );
+
+        // 索引指向的区块不在高度索引中时报告数据损坏
+        blockchain
+            .get_storage()
+            .remove(HEIGHTS_TREE, height_key(2).as_slice())
+            .unwrap();
+        assert!(matches!(
+            blockchain.get_address_history(other_pub_key_hash.as_slice()),
+            Err(Error::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_height_index() {
+        let blockchain = new_memory_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+        let block1 = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 1, 0)])
+            .unwrap();
+        let block2 = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(GENESIS_ADDRESS, 2, 0)])
+            .unwrap();
+        assert_eq!(blockchain.get_block_hash(0).unwrap(), genesis_hash);
+        assert_eq!(
+            blockchain
+                .get_block_by_height(2)
+                .unwrap()
+                .unwrap()
+                .get_hash(),
+            block2.get_hash()
+        );
+        assert!(blockchain.get_block_hash(3).is_none());
+
+        let heights: Vec<usize> = blockchain
+            .range_iterator(1, usize::MAX)
+            .map(|block| block.unwrap().get_height())
+            .collect();
+        assert_eq!(heights, vec![1, 2]);
+        let hashes: Vec<String> = blockchain
+            .range_iterator(0, 1)
+            .map(|block| String::from(block.unwrap().get_hash()))
+            .collect();
+        assert_eq!(
+            hashes,
+            vec![genesis_hash.clone(), String::from(block1.get_hash())]
+        );
+
+        // 链重组后高度索引指向新分支
+        let fork = mine_fork(&blockchain, genesis_hash.as_str(), 3, GENESIS_ADDRESS);
+        assert_eq!(blockchain.get_block_hash(3).unwrap(), fork[2].get_hash());
+        assert_ne!(blockchain.get_block_hash(1).unwrap(), block1.get_hash());
+        assert_eq!(blockchain.range_iterator(0, usize::MAX).count(), 4);
     }
 
     #[test]
     fn test_find_transaction() {
-        let blockchain = super::Blockchain::new_blockchain();
+        let blockchain = new_memory_blockchain();
         let trasaction = blockchain.find_transaction(
             "00aee463227e52bf2c6986033d86a2572942f9d79a1da7c4cebe790a8b8ead92".as_bytes(),
         );
-        assert!(trasaction.is_none())
+        assert!(trasaction.unwrap().is_none())
     }
 }
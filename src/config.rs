@@ -1,6 +1,7 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 use std::sync::RwLock;
 
 pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(|| Config::new());
@@ -10,6 +11,33 @@ static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
 
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+const SUBSIDY_HALVING_INTERVAL_KEY: &str = "SUBSIDY_HALVING_INTERVAL";
+const COINBASE_MATURITY_KEY: &str = "COINBASE_MATURITY";
+const MAX_MEMPOOL_SIZE_KEY: &str = "MAX_MEMPOOL_SIZE";
+const MIN_RELAY_FEE_KEY: &str = "MIN_RELAY_FEE";
+const MEMPOOL_EXPIRY_KEY: &str = "MEMPOOL_EXPIRY";
+const DATA_DIR_KEY: &str = "DATA_DIR";
+const TXINDEX_KEY: &str = "TXINDEX";
+const ADDRINDEX_KEY: &str = "ADDRINDEX";
+const CHECK_BLOCKS_KEY: &str = "CHECK_BLOCKS";
+
+/// 默认每隔 210000 个区块挖矿奖励减半（与比特币相同）
+const DEFAULT_SUBSIDY_HALVING_INTERVAL: usize = 210_000;
+
+/// 默认 coinbase 交易的输出需要经过 100 个区块才能被花费（与比特币相同）
+const DEFAULT_COINBASE_MATURITY: usize = 100;
+
+/// 默认内存池中的交易最多占用 10 MB
+const DEFAULT_MAX_MEMPOOL_SIZE: usize = 10 * 1000 * 1000;
+
+/// 默认不要求交易支付手续费
+const DEFAULT_MIN_RELAY_FEE: i32 = 0;
+
+/// 默认内存池中的交易 14 天后过期（与比特币相同），单位为秒
+const DEFAULT_MEMPOOL_EXPIRY: i64 = 14 * 24 * 60 * 60;
+
+/// 默认节点启动时检查最近 6 个区块，检查整条主链需要读取所有区块，链较长时启动很慢
+const DEFAULT_CHECK_BLOCKS: usize = 6;
 
 /// Node 配置
 pub struct Config {
@@ -25,6 +53,41 @@ impl Config {
         }
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        // 从环境变量获取挖矿奖励减半间隔
+        if let Ok(interval) = env::var(SUBSIDY_HALVING_INTERVAL_KEY) {
+            map.insert(String::from(SUBSIDY_HALVING_INTERVAL_KEY), interval);
+        }
+        // 从环境变量获取 coinbase 成熟度
+        if let Ok(maturity) = env::var(COINBASE_MATURITY_KEY) {
+            map.insert(String::from(COINBASE_MATURITY_KEY), maturity);
+        }
+        // 从环境变量获取内存池大小上限及最低转发手续费
+        if let Ok(size) = env::var(MAX_MEMPOOL_SIZE_KEY) {
+            map.insert(String::from(MAX_MEMPOOL_SIZE_KEY), size);
+        }
+        if let Ok(fee) = env::var(MIN_RELAY_FEE_KEY) {
+            map.insert(String::from(MIN_RELAY_FEE_KEY), fee);
+        }
+        // 从环境变量获取内存池交易的过期时间
+        if let Ok(expiry) = env::var(MEMPOOL_EXPIRY_KEY) {
+            map.insert(String::from(MEMPOOL_EXPIRY_KEY), expiry);
+        }
+        // 从环境变量获取数据目录
+        if let Ok(dir) = env::var(DATA_DIR_KEY) {
+            map.insert(String::from(DATA_DIR_KEY), dir);
+        }
+        // 从环境变量获取是否建立交易索引
+        if let Ok(txindex) = env::var(TXINDEX_KEY) {
+            map.insert(String::from(TXINDEX_KEY), txindex);
+        }
+        // 从环境变量获取是否建立地址索引
+        if let Ok(addrindex) = env::var(ADDRINDEX_KEY) {
+            map.insert(String::from(ADDRINDEX_KEY), addrindex);
+        }
+        // 从环境变量获取节点启动时检查的区块数量
+        if let Ok(check_blocks) = env::var(CHECK_BLOCKS_KEY) {
+            map.insert(String::from(CHECK_BLOCKS_KEY), check_blocks);
+        }
 
         Config {
             inner: RwLock::new(map),
@@ -52,6 +115,115 @@ impl Config {
         None
     }
 
+    /// 获取挖矿奖励减半间隔
+    pub fn get_subsidy_halving_interval(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        if let Some(interval) = inner.get(SUBSIDY_HALVING_INTERVAL_KEY) {
+            if let Ok(interval) = interval.parse() {
+                if interval > 0 {
+                    return interval;
+                }
+            }
+        }
+        DEFAULT_SUBSIDY_HALVING_INTERVAL
+    }
+
+    /// 设置 coinbase 成熟度
+    pub fn set_coinbase_maturity(&self, maturity: usize) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(COINBASE_MATURITY_KEY), maturity.to_string());
+    }
+
+    /// 获取 coinbase 成熟度，即 coinbase 输出可以被花费前需要经过的区块数
+    pub fn get_coinbase_maturity(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        if let Some(maturity) = inner.get(COINBASE_MATURITY_KEY) {
+            if let Ok(maturity) = maturity.parse() {
+                return maturity;
+            }
+        }
+        DEFAULT_COINBASE_MATURITY
+    }
+
+    /// 获取内存池中交易序列化后的总字节数上限
+    pub fn get_max_mempool_size(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        if let Some(size) = inner.get(MAX_MEMPOOL_SIZE_KEY) {
+            if let Ok(size) = size.parse() {
+                return size;
+            }
+        }
+        DEFAULT_MAX_MEMPOOL_SIZE
+    }
+
+    /// 获取最低转发手续费，即每 1000 字节交易需要支付的手续费
+    pub fn get_min_relay_fee(&self) -> i32 {
+        let inner = self.inner.read().unwrap();
+        if let Some(fee) = inner.get(MIN_RELAY_FEE_KEY) {
+            if let Ok(fee) = fee.parse() {
+                if fee >= 0 {
+                    return fee;
+                }
+            }
+        }
+        DEFAULT_MIN_RELAY_FEE
+    }
+
+    /// 获取内存池交易的过期时间（秒），交易在内存池中停留超过该时间后被移除
+    pub fn get_mempool_expiry(&self) -> i64 {
+        let inner = self.inner.read().unwrap();
+        if let Some(expiry) = inner.get(MEMPOOL_EXPIRY_KEY) {
+            if let Ok(expiry) = expiry.parse() {
+                if expiry > 0 {
+                    return expiry;
+                }
+            }
+        }
+        DEFAULT_MEMPOOL_EXPIRY
+    }
+
+    /// 设置数据目录
+    pub fn set_data_dir(&self, dir: String) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(DATA_DIR_KEY), dir);
+    }
+
+    /// 获取数据目录，区块链数据库和钱包文件都保存在该目录下，默认为当前目录
+    pub fn get_data_dir(&self) -> PathBuf {
+        let inner = self.inner.read().unwrap();
+        if let Some(dir) = inner.get(DATA_DIR_KEY) {
+            return PathBuf::from(dir);
+        }
+        env::current_dir().unwrap()
+    }
+
+    /// 是否建立交易索引，TXINDEX=1 时打开区块链会自动建立交易索引
+    pub fn is_txindex(&self) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner
+            .get(TXINDEX_KEY)
+            .is_some_and(|txindex| txindex.eq("1") || txindex.eq("true"))
+    }
+
+    /// 是否建立地址索引，ADDRINDEX=1 时打开区块链会自动建立地址索引
+    pub fn is_addrindex(&self) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner
+            .get(ADDRINDEX_KEY)
+            .is_some_and(|addrindex| addrindex.eq("1") || addrindex.eq("true"))
+    }
+
+    /// 获取节点启动时完整性检查的区块数量，0 表示检查整条主链（包括 UTXO 集）
+    pub fn get_check_blocks(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        if let Some(check_blocks) = inner.get(CHECK_BLOCKS_KEY) {
+            if let Ok(check_blocks) = check_blocks.parse() {
+                return check_blocks;
+            }
+        }
+        DEFAULT_CHECK_BLOCKS
+    }
+
     /// 检查矿工节点
     pub fn is_miner(&self) -> bool {
         let inner = self.inner.read().unwrap();
@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// 库中可能失败的操作返回的错误
+#[derive(Debug)]
+pub enum Error {
+    /// 数据目录中还没有创建区块链
+    BlockchainNotFound,
+    /// 钱包文件中没有该地址对应的钱包
+    WalletNotFound(String),
+    /// 地址格式或校验和无效
+    InvalidAddress(String),
+    /// 可花费的输出不足以支付金额和手续费
+    InsufficientFunds { required: i32, available: i32 },
+    /// 交易无效
+    InvalidTransaction(String),
+    /// 区块无效
+    InvalidBlock(String),
+    /// 挖矿期间最新区块已经改变，新区块不再扩展主链
+    TipChanged,
+    /// 数据库中的区块链数据缺失或不一致
+    Corrupted(String),
+    /// 数据库由不兼容的旧版本创建，区块格式不同
+    IncompatibleDatabase,
+    /// base58 解码失败
+    Base58(bs58::decode::Error),
+    /// 序列化或反序列化失败
+    Serialization(bincode::Error),
+    /// 数据库错误
+    Storage(sled::Error),
+    /// 文件读写错误
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BlockchainNotFound => {
+                write!(f, "no existing blockchain found, create one first")
+            }
+            Error::WalletNotFound(address) => write!(f, "no wallet found for address {}", address),
+            Error::InvalidAddress(address) => write!(f, "address {} is not valid", address),
+            Error::InsufficientFunds {
+                required,
+                available,
+            } => write!(
+                f,
+                "not enough funds: required {}, available {}",
+                required, available
+            ),
+            Error::InvalidTransaction(reason) => write!(f, "invalid transaction: {}", reason),
+            Error::InvalidBlock(reason) => write!(f, "invalid block: {}", reason),
+            Error::TipChanged => write!(f, "the chain tip changed while mining"),
+            Error::Corrupted(reason) => write!(f, "corrupted blockchain data: {}", reason),
+            Error::IncompatibleDatabase => write!(
+                f,
+                "the blockchain database was created by an incompatible version, \
+                 remove the data directory and create or download the blockchain again"
+            ),
+            Error::Base58(e) => write!(f, "base58 decode error: {}", e),
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Base58(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::Storage(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<bs58::decode::Error> for Error {
+    fn from(e: bs58::decode::Error) -> Self {
+        Error::Base58(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,20 +1,42 @@
+mod error;
+pub use error::Error;
+pub use error::Result;
+
+mod storage;
+pub use storage::MemoryStorage;
+pub use storage::SledStorage;
+pub use storage::Storage;
+pub use storage::StorageIter;
+pub use storage::WriteBatch;
+
 mod block;
 use block::Block;
+use block::BlockHeader;
+pub use block::MAX_BLOCK_SIZE;
 
 mod blockchain;
+pub use blockchain::AddressHistoryEntry;
 pub use blockchain::Blockchain;
+pub use blockchain::ChainInconsistency;
 
 mod utxo_set;
 pub use utxo_set::UTXOSet;
 
+mod merkle;
+pub use merkle::MerkleProof;
+pub use merkle::MerkleTree;
+
 mod proof_of_work;
-use proof_of_work::ProofOfWork;
+pub use proof_of_work::ProofOfWork;
 
 mod transaction;
+pub use transaction::get_block_subsidy;
+pub use transaction::get_scheduled_supply;
 pub use transaction::Transaction;
 
 mod wallet;
 pub use wallet::convert_address;
+pub use wallet::decode_address;
 pub use wallet::hash_pub_key;
 pub use wallet::validate_address;
 pub use wallet::Wallet;
@@ -35,6 +57,9 @@ pub use node::Nodes;
 mod memory_pool;
 pub use memory_pool::BlockInTransit;
 pub use memory_pool::MemoryPool;
+pub use memory_pool::OrphanBlockPool;
+pub use memory_pool::OrphanPool;
+pub use memory_pool::RejectReason;
 
 mod config;
 pub use config::Config;
@@ -49,3 +74,5 @@ use utils::ecdsa_p256_sha256_sign_verify;
 use utils::new_key_pair;
 use utils::ripemd160_digest;
 use utils::sha256_digest;
+#[cfg(test)]
+use utils::temp_data_dir;
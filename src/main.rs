@@ -1,6 +1,7 @@
 use blockchain_rust::{
-    convert_address, hash_pub_key, send_tx, utils, validate_address, Blockchain, Server,
-    Transaction, UTXOSet, Wallets, ADDRESS_CHECK_SUM_LEN, CENTERAL_NODE, GLOBAL_CONFIG,
+    convert_address, decode_address, get_scheduled_supply, hash_pub_key, send_tx, validate_address,
+    Blockchain, ChainInconsistency, MerkleProof, Server, Transaction, UTXOSet, Wallets,
+    CENTERAL_NODE, GLOBAL_CONFIG,
 };
 use data_encoding::HEXLOWER;
 use log::LevelFilter;
@@ -12,6 +13,11 @@ const MINE_TRUE: usize = 1;
 #[derive(Debug, StructOpt)]
 #[structopt(name = "blockchain_rust")]
 struct Opt {
+    #[structopt(
+        long = "datadir",
+        help = "Directory holding the blockchain database and wallet.dat"
+    )]
+    datadir: Option<String>,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -43,6 +49,8 @@ enum Command {
         to: String,
         #[structopt(name = "amount", help = "Amount to send")]
         amount: i32,
+        #[structopt(long = "fee", default_value = "0", help = "Fee paid to the miner")]
+        fee: i32,
         #[structopt(name = "mine", help = "Mine immediately on the same node")]
         mine: usize,
     },
@@ -50,6 +58,67 @@ enum Command {
     Printchain,
     #[structopt(name = "reindexutxo", about = "rebuild UTXO index set")]
     Reindexutxo,
+    #[structopt(
+        name = "reindextx",
+        about = "Rebuild the transaction index used to look up transactions by id"
+    )]
+    Reindextx,
+    #[structopt(
+        name = "reindexaddr",
+        about = "Rebuild the address index used to look up the transaction history of an address"
+    )]
+    Reindexaddr,
+    #[structopt(
+        name = "gethistory",
+        about = "List every transaction touching the address with the running balance"
+    )]
+    GetHistory {
+        #[structopt(name = "address", help = "The wallet address")]
+        address: String,
+    },
+    #[structopt(
+        name = "gettxoutproof",
+        about = "Get the merkle proof that a transaction is included in a block"
+    )]
+    GetTxOutProof {
+        #[structopt(name = "txid", help = "The transaction id")]
+        txid: String,
+    },
+    #[structopt(
+        name = "verifytxoutproof",
+        about = "Verify the merkle proof of a transaction against the local chain"
+    )]
+    VerifyTxOutProof {
+        #[structopt(
+            name = "blockhash",
+            help = "The hash of the block containing the transaction"
+        )]
+        block_hash: String,
+        #[structopt(name = "proof", help = "The hex encoded merkle proof")]
+        proof: String,
+    },
+    #[structopt(
+        name = "gettxoutsetinfo",
+        about = "Print statistics about the UTXO set and the total supply"
+    )]
+    GetTxOutSetInfo,
+    #[structopt(
+        name = "verifychain",
+        about = "Check the blocks and the UTXO set of the main chain for inconsistencies"
+    )]
+    VerifyChain {
+        #[structopt(
+            long = "depth",
+            default_value = "0",
+            help = "Number of blocks to check from the tip, 0 checks the whole chain"
+        )]
+        depth: usize,
+        #[structopt(
+            long = "rollback",
+            help = "Roll back to the block before the first inconsistent block"
+        )]
+        rollback: bool,
+    },
     #[structopt(name = "startnode", about = "Start a node")]
     StartNode {
         #[structopt(name = "miner", help = "Enable mining mode and send reward to ADDRESS")]
@@ -60,11 +129,16 @@ enum Command {
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let opt = Opt::from_args();
+    if let Some(dir) = opt.datadir {
+        GLOBAL_CONFIG.set_data_dir(dir);
+    }
     match opt.command {
         Command::Createblockchain { address } => {
             let blockchain = Blockchain::create_blockchain(address.as_str());
             let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
+            utxo_set
+                .reindex()
+                .expect("ERROR: unable to rebuild the UTXO set");
             println!("Done!");
         }
         Command::Createwallet => {
@@ -73,21 +147,18 @@ fn main() {
             println!("Your new address: {}", address)
         }
         Command::GetBalance { address } => {
-            let address_valid = validate_address(address.as_str());
-            if address_valid == false {
-                panic!("ERROR: Address is not valid")
-            }
-            let payload = utils::base58_decode(address.as_str());
-            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+            let pub_key_hash = match decode_address(address.as_str()) {
+                Ok(pub_key_hash) => pub_key_hash,
+                Err(e) => panic!("ERROR: {}", e),
+            };
 
             let blockchain = Blockchain::new_blockchain();
             let utxo_set = UTXOSet::new(blockchain);
-            let utxos = utxo_set.find_utxo(pub_key_hash);
-            let mut balance = 0;
-            for utxo in utxos {
-                balance += utxo.get_value();
-            }
-            println!("Balance of {}: {}", address, balance);
+            // 未成熟的 coinbase 输出暂时不能花费
+            let (spendable, immature) = utxo_set.get_balance(pub_key_hash.as_slice());
+            println!("Balance of {}: {}", address, spendable + immature);
+            println!("- Spendable: {}", spendable);
+            println!("- Immature: {}", immature);
         }
         Command::ListAddresses => {
             let wallets = Wallets::new();
@@ -99,6 +170,7 @@ fn main() {
             from,
             to,
             amount,
+            fee,
             mine,
         } => {
             if !validate_address(from.as_str()) {
@@ -107,19 +179,31 @@ fn main() {
             if !validate_address(to.as_str()) {
                 panic!("ERROR: Recipient address is not valid")
             }
+            if fee < 0 {
+                panic!("ERROR: Fee must not be negative")
+            }
             let blockchain = Blockchain::new_blockchain();
             let utxo_set = UTXOSet::new(blockchain.clone());
             // 创建 UTXO 交易
-            let transaction =
-                Transaction::new_utxo_transaction(from.as_str(), to.as_str(), amount, &utxo_set);
+            let transaction = Transaction::new_utxo_transaction(
+                from.as_str(),
+                to.as_str(),
+                amount,
+                fee,
+                &utxo_set,
+            );
 
             if mine == MINE_TRUE {
-                // 挖矿奖励
-                let coinbase_tx = Transaction::new_coinbase_tx(from.as_str());
-                // 挖新区块
-                let block = blockchain.mine_block(&vec![transaction, coinbase_tx]);
-                // 更新 UTXO 集
-                utxo_set.update(&block);
+                // 挖矿奖励及手续费
+                let coinbase_tx = Transaction::new_coinbase_tx(
+                    from.as_str(),
+                    blockchain.get_best_height() + 1,
+                    fee,
+                );
+                // 挖新区块，UTXO 集随区块一起更新
+                if let Err(e) = blockchain.mine_block(&vec![transaction, coinbase_tx]) {
+                    panic!("ERROR: {}", e)
+                }
             } else {
                 send_tx(CENTERAL_NODE, &transaction);
             }
@@ -128,11 +212,11 @@ fn main() {
         Command::Printchain => {
             let mut block_iterator = Blockchain::new_blockchain().iterator();
             loop {
-                let option = block_iterator.next();
-                if option.is_none() {
-                    break;
-                }
-                let block = option.unwrap();
+                let block = match block_iterator.next() {
+                    Ok(Some(block)) => block,
+                    Ok(None) => break,
+                    Err(e) => panic!("ERROR: {}", e),
+                };
                 println!("Pre block hash: {}", block.get_pre_block_hash());
                 println!("Cur block hash: {}", block.get_hash());
                 println!("Cur block Timestamp: {}", block.get_timestamp());
@@ -165,10 +249,139 @@ fn main() {
         Command::Reindexutxo => {
             let blockchain = Blockchain::new_blockchain();
             let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
+            utxo_set
+                .reindex()
+                .expect("ERROR: unable to rebuild the UTXO set");
             let count = utxo_set.count_transactions();
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
+        Command::Reindextx => {
+            let blockchain = Blockchain::new_blockchain();
+            let count = blockchain
+                .reindex_transactions()
+                .expect("ERROR: unable to rebuild the transaction index");
+            println!(
+                "Done! There are {} transactions in the transaction index.",
+                count
+            );
+        }
+        Command::Reindexaddr => {
+            let blockchain = Blockchain::new_blockchain();
+            let count = blockchain
+                .reindex_addresses()
+                .expect("ERROR: unable to rebuild the address index");
+            println!("Done! There are {} addresses in the address index.", count);
+        }
+        Command::GetHistory { address } => {
+            let pub_key_hash = match decode_address(address.as_str()) {
+                Ok(pub_key_hash) => pub_key_hash,
+                Err(e) => panic!("ERROR: {}", e),
+            };
+            let blockchain = Blockchain::new_blockchain();
+            let has_addrindex = blockchain
+                .has_addrindex()
+                .expect("ERROR: unable to read the address index");
+            if !has_addrindex {
+                panic!("ERROR: The address index is not built, run reindexaddr or set ADDRINDEX=1")
+            }
+            let history = match blockchain.get_address_history(pub_key_hash.as_slice()) {
+                Ok(history) => history,
+                Err(e) => panic!("ERROR: {}", e),
+            };
+            println!("History of {}:", address);
+            for entry in history {
+                println!(
+                    "- Height = {}, txid = {}, received = {}, spent = {}, balance = {}",
+                    entry.get_height(),
+                    HEXLOWER.encode(entry.get_txid()),
+                    entry.get_received(),
+                    entry.get_spent(),
+                    entry.get_balance(),
+                );
+            }
+        }
+        Command::GetTxOutProof { txid } => {
+            let txid = HEXLOWER
+                .decode(txid.as_bytes())
+                .expect("ERROR: Transaction id is not valid");
+            let blockchain = Blockchain::new_blockchain();
+            match blockchain.get_tx_out_proof(txid.as_slice()) {
+                Ok(Some((block_hash, proof))) => {
+                    println!("Block hash: {}", block_hash);
+                    println!("Proof: {}", HEXLOWER.encode(proof.serialize().as_slice()));
+                }
+                Ok(None) => println!("Transaction not found in the blockchain"),
+                Err(e) => panic!("ERROR: {}", e),
+            }
+        }
+        Command::VerifyTxOutProof { block_hash, proof } => {
+            // 证明来自用户输入，格式错误时不能 panic
+            let proof = match HEXLOWER.decode(proof.as_bytes()) {
+                Ok(proof_bytes) => MerkleProof::try_deserialize(proof_bytes.as_slice()).ok(),
+                Err(_) => None,
+            };
+            let Some(proof) = proof else {
+                println!("Proof is not valid");
+                return;
+            };
+            let blockchain = Blockchain::new_blockchain();
+            let valid = blockchain
+                .verify_tx_out_proof(block_hash.as_str(), &proof)
+                .expect("ERROR: unable to read the block");
+            if valid {
+                println!("{}", HEXLOWER.encode(proof.get_txid()));
+            } else {
+                println!("Proof is not valid");
+            }
+        }
+        Command::GetTxOutSetInfo => {
+            let blockchain = Blockchain::new_blockchain();
+            let height = blockchain.get_best_height();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            println!("Height: {}", height);
+            println!("Best block: {}", blockchain.get_tip_hash());
+            println!("Transactions: {}", utxo_set.count_transactions());
+            println!("Transaction outputs: {}", utxo_set.count_outputs());
+            println!("Total amount: {}", utxo_set.get_total_amount());
+            println!(
+                "UTXO set hash: {}",
+                HEXLOWER.encode(utxo_set.get_hash().as_slice())
+            );
+            println!("Scheduled supply: {}", get_scheduled_supply(height));
+        }
+        Command::VerifyChain { depth, rollback } => {
+            let blockchain = Blockchain::new_blockchain();
+            let inconsistency = match blockchain.verify_chain(depth) {
+                Ok(()) => {
+                    println!("The blockchain is consistent.");
+                    return;
+                }
+                Err(inconsistency) => inconsistency,
+            };
+            println!("Inconsistency found: {}", inconsistency);
+            if !rollback {
+                return;
+            }
+            match inconsistency {
+                ChainInconsistency::Block { hash, .. } => {
+                    let count = blockchain
+                        .rollback_to(hash.as_str())
+                        .expect("ERROR: unable to roll back the blockchain");
+                    println!(
+                        "Done! Removed {} blocks, the best block is now {}.",
+                        count,
+                        blockchain.get_tip_hash()
+                    );
+                }
+                ChainInconsistency::UTXOSet { .. } => {
+                    let utxo_set = UTXOSet::new(blockchain);
+                    utxo_set
+                        .reindex()
+                        .expect("ERROR: unable to rebuild the UTXO set");
+                    println!("Done! The UTXO set is rebuilt.");
+                }
+            }
+        }
         Command::StartNode { miner } => {
             if let Some(addr) = miner {
                 if validate_address(addr.as_str()) == false {
@@ -178,6 +391,12 @@ fn main() {
                 GLOBAL_CONFIG.set_mining_addr(addr);
             }
             let blockchain = Blockchain::new_blockchain();
+            if let Err(inconsistency) = blockchain.verify_chain(GLOBAL_CONFIG.get_check_blocks()) {
+                panic!(
+                    "ERROR: {}, run verifychain --rollback to repair the blockchain",
+                    inconsistency
+                )
+            }
             let sockert_addr = GLOBAL_CONFIG.get_node_addr();
             Server::new(blockchain).run(sockert_addr.as_str());
         }
@@ -1,53 +1,710 @@
-use crate::Transaction;
+use crate::wallet::hash_pub_key;
+use crate::{Block, Blockchain, Transaction, UTXOSet, WriteBatch, GLOBAL_CONFIG};
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use log::error;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::RwLock;
 
-/// 交易内存池 ( K -> txid_hex, V => Transaction )
+/// 节点关闭时保存的内存池交易 ( K -> txid, V -> (到达时间, Transaction) )
+const MEMPOOL_TREE: &str = "mempool";
+
+/// 孤儿交易池最多保存的交易数量
+const MAX_ORPHAN_TRANSACTIONS: usize = 100;
+
+/// 孤儿交易序列化后的最大字节数
+const MAX_ORPHAN_TX_SIZE: usize = 100 * 1000;
+
+/// 孤儿交易的过期时间（毫秒）
+const ORPHAN_EXPIRY: i64 = 20 * 60 * 1000;
+
+/// 孤儿区块池最多保存的区块数量
+const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// 交易被内存池拒绝的原因
+#[derive(Debug, PartialEq)]
+pub enum RejectReason {
+    Coinbase,                    // coinbase 交易只能由矿工打包进区块
+    AlreadyKnown,                // 交易已经在内存池中
+    InvalidId,                   // 交易ID与交易内容不一致
+    NegativeOutput,              // 交易输出金额为负数
+    NoInputs,                    // 非 coinbase 交易没有输入
+    ValueOverflow,               // 输入或输出总额溢出
+    DuplicateInput,              // 交易重复花费自身的某个输入
+    Conflict(String),            // 输入已被内存池中的另一笔交易花费
+    MissingInputs(Vec<Vec<u8>>), // 输入引用的父交易不在区块链或内存池中，即孤儿交易
+    SpentInputs,                 // 输入已被花费
+    ImmatureCoinbase,            // 输入引用了未成熟的 coinbase 输出
+    NotOwned,                    // 输入不属于交易中的公钥
+    InsufficientInputs,          // 输出总额超过输入总额
+    InvalidSignature,            // 签名验证失败
+    InsufficientFee,             // 手续费低于最低转发手续费
+    MempoolFull,                 // 内存池已满，且交易的费率不足以驱逐其他交易
+    Expired,                     // 交易在内存池中停留的时间超过过期时间
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Coinbase => write!(f, "coinbase transaction is not accepted"),
+            RejectReason::AlreadyKnown => write!(f, "transaction is already in the pool"),
+            RejectReason::InvalidId => write!(f, "transaction id does not match its content"),
+            RejectReason::NegativeOutput => write!(f, "transaction has a negative output value"),
+            RejectReason::NoInputs => write!(f, "transaction has no inputs"),
+            RejectReason::ValueOverflow => write!(f, "transaction values overflow"),
+            RejectReason::DuplicateInput => write!(f, "transaction spends the same output twice"),
+            RejectReason::Conflict(txid_hex) => {
+                write!(f, "input is already spent by pool transaction {}", txid_hex)
+            }
+            RejectReason::MissingInputs(_) => write!(f, "previous transaction is not found"),
+            RejectReason::SpentInputs => write!(f, "input is already spent or does not exist"),
+            RejectReason::ImmatureCoinbase => write!(f, "input spends an immature coinbase output"),
+            RejectReason::NotOwned => write!(f, "input is not owned by its public key"),
+            RejectReason::InsufficientInputs => {
+                write!(f, "transaction spends more than its inputs")
+            }
+            RejectReason::InvalidSignature => write!(f, "transaction signature is not valid"),
+            RejectReason::InsufficientFee => write!(f, "transaction fee is below the minimum"),
+            RejectReason::MempoolFull => write!(f, "memory pool is full"),
+            RejectReason::Expired => write!(f, "transaction is expired"),
+        }
+    }
+}
+
+/// 内存池中的交易，以及交易的手续费、序列化后的字节数和到达内存池的时间
+#[derive(Clone)]
+struct MemoryPoolEntry {
+    tx: Transaction,
+    fee: i32,
+    size: usize,
+    time: i64,
+}
+
+impl MemoryPoolEntry {
+    fn new(tx: Transaction, fee: i32, time: i64) -> MemoryPoolEntry {
+        let size = tx.serialize().len();
+        MemoryPoolEntry {
+            tx,
+            fee,
+            size,
+            time,
+        }
+    }
+
+    /// 比较两笔交易的费率（每字节手续费），交叉相乘避免浮点运算
+    fn cmp_fee_rate(&self, other: &MemoryPoolEntry) -> Ordering {
+        let left = self.fee as i64 * other.size as i64;
+        let right = other.fee as i64 * self.size as i64;
+        left.cmp(&right)
+    }
+}
+
+/// 内存池的数据，交易和交易花费的输出索引
+struct MemoryPoolInner {
+    txs: HashMap<String, MemoryPoolEntry>, // K -> txid_hex, V -> MemoryPoolEntry
+    spent_outputs: HashMap<(Vec<u8>, usize), String>, // K -> (txid, vout), V -> 花费该输出的 txid_hex
+    total_size: usize,                                // 内存池中交易的总字节数
+    max_size: usize,                                  // 内存池中交易的总字节数上限
+}
+
+impl MemoryPoolInner {
+    fn insert(&mut self, entry: MemoryPoolEntry) {
+        let txid_hex = HEXLOWER.encode(entry.tx.get_id());
+        if !entry.tx.is_coinbase() {
+            for vin in entry.tx.get_vin() {
+                self.spent_outputs
+                    .insert((vin.get_txid().to_vec(), vin.get_vout()), txid_hex.clone());
+            }
+        }
+        self.total_size += entry.size;
+        if let Some(old) = self.txs.insert(txid_hex, entry) {
+            self.total_size -= old.size;
+        }
+    }
+
+    fn remove(&mut self, txid_hex: &str) {
+        if let Some(entry) = self.txs.remove(txid_hex) {
+            self.total_size -= entry.size;
+            for vin in entry.tx.get_vin() {
+                let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+                if let Some(spender) = self.spent_outputs.get(&outpoint) {
+                    if spender.eq(txid_hex) {
+                        self.spent_outputs.remove(&outpoint);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 交易及其在内存池中的所有后代交易（花费了它的输出的交易）
+    fn descendants(&self, txid_hex: &str) -> Vec<String> {
+        let mut result = vec![];
+        let mut stack = vec![String::from(txid_hex)];
+        while let Some(current) = stack.pop() {
+            let entry = match self.txs.get(current.as_str()) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if result.contains(&current) {
+                continue;
+            }
+            for idx in 0..entry.tx.get_vout().len() {
+                let outpoint = (entry.tx.get_id_bytes(), idx);
+                if let Some(spender) = self.spent_outputs.get(&outpoint) {
+                    stack.push(spender.clone());
+                }
+            }
+            result.push(current);
+        }
+        result
+    }
+
+    /// 移除交易及其后代交易，父交易不存在后代交易将无法被打包
+    fn remove_with_descendants(&mut self, txid_hex: &str) {
+        for descendant in self.descendants(txid_hex) {
+            self.remove(descendant.as_str());
+        }
+    }
+
+    /// 按费率从高到低排列的交易
+    fn sorted_entries(&self) -> Vec<&MemoryPoolEntry> {
+        let mut entries: Vec<&MemoryPoolEntry> = self.txs.values().collect();
+        entries.sort_by(|a, b| b.cmp_fee_rate(a));
+        entries
+    }
+
+    /// 为新交易腾出空间，驱逐费率低于新交易的交易，空间不足时不做任何驱逐并返回 false
+    fn make_room(&mut self, entry: &MemoryPoolEntry) -> bool {
+        if self.total_size + entry.size <= self.max_size {
+            return true;
+        }
+        let mut evicted: Vec<String> = vec![];
+        let mut freed = 0;
+        for victim in self.sorted_entries().into_iter().rev() {
+            if self.total_size + entry.size - freed <= self.max_size {
+                break;
+            }
+            if victim.cmp_fee_rate(entry) != Ordering::Less {
+                return false;
+            }
+            // 驱逐交易时一并驱逐其后代交易，但不能驱逐新交易依赖的父交易
+            let descendants = self.descendants(HEXLOWER.encode(victim.tx.get_id()).as_str());
+            let is_ancestor = entry
+                .tx
+                .get_vin()
+                .iter()
+                .any(|vin| descendants.contains(&HEXLOWER.encode(vin.get_txid())));
+            if is_ancestor {
+                continue;
+            }
+            for txid_hex in descendants {
+                if !evicted.contains(&txid_hex) {
+                    freed += self.txs[txid_hex.as_str()].size;
+                    evicted.push(txid_hex);
+                }
+            }
+        }
+        if self.total_size + entry.size - freed > self.max_size {
+            return false;
+        }
+        for txid_hex in evicted {
+            self.remove(txid_hex.as_str());
+        }
+        true
+    }
+}
+
+/// 交易内存池 ( K -> txid_hex, V => Transaction )，交易按费率排序，总字节数超过上限时驱逐费率最低的交易
 pub struct MemoryPool {
-    inner: RwLock<HashMap<String, Transaction>>,
+    inner: RwLock<MemoryPoolInner>,
+    min_relay_fee: i32, // 每 1000 字节交易需要支付的最低手续费
+    expiry: i64,        // 交易的过期时间（毫秒）
 }
 
 impl MemoryPool {
     pub fn new() -> MemoryPool {
+        MemoryPool::with_limits(
+            GLOBAL_CONFIG.get_max_mempool_size(),
+            GLOBAL_CONFIG.get_min_relay_fee(),
+            GLOBAL_CONFIG.get_mempool_expiry(),
+        )
+    }
+
+    /// 创建指定字节数上限、最低转发手续费和过期时间（秒）的内存池
+    pub fn with_limits(max_size: usize, min_relay_fee: i32, expiry: i64) -> MemoryPool {
         MemoryPool {
-            inner: RwLock::new(HashMap::new()),
+            inner: RwLock::new(MemoryPoolInner {
+                txs: HashMap::new(),
+                spent_outputs: HashMap::new(),
+                total_size: 0,
+                max_size,
+            }),
+            min_relay_fee,
+            expiry: expiry * 1000,
         }
     }
 
     pub fn containes(&self, txid_hex: &str) -> bool {
-        self.inner.read().unwrap().contains_key(txid_hex)
+        self.inner.read().unwrap().txs.contains_key(txid_hex)
     }
 
+    /// 不经校验直接加入交易，手续费记为 0
     pub fn add(&self, tx: Transaction) {
+        self.inner
+            .write()
+            .unwrap()
+            .insert(MemoryPoolEntry::new(tx, 0, crate::current_timestamp()));
+    }
+
+    /// 校验交易后加入内存池，交易的输入必须是 UTXO 集中已成熟的输出或内存池中交易的输出，并且没有被内存池中的其他交易花费
+    pub fn accept(&self, tx: Transaction, utxo_set: &UTXOSet) -> Result<(), RejectReason> {
+        self.accept_at(tx, crate::current_timestamp(), utxo_set)
+    }
+
+    /// 校验到达时间为 time 的交易后加入内存池
+    fn accept_at(
+        &self,
+        tx: Transaction,
+        time: i64,
+        utxo_set: &UTXOSet,
+    ) -> Result<(), RejectReason> {
+        let mut inner = self.inner.write().unwrap();
+        if time + self.expiry <= crate::current_timestamp() {
+            return Err(RejectReason::Expired);
+        }
+        if tx.is_coinbase() {
+            return Err(RejectReason::Coinbase);
+        }
         let txid_hex = HEXLOWER.encode(tx.get_id());
-        self.inner.write().unwrap().insert(txid_hex, tx);
+        if inner.txs.contains_key(txid_hex.as_str()) {
+            return Err(RejectReason::AlreadyKnown);
+        }
+        if !tx.is_id_valid() {
+            return Err(RejectReason::InvalidId);
+        }
+        if tx.get_vout().iter().any(|out| out.get_value() < 0) {
+            return Err(RejectReason::NegativeOutput);
+        }
+        if tx.get_vin().is_empty() {
+            return Err(RejectReason::NoInputs);
+        }
+        let blockchain = utxo_set.get_blockchain();
+        let spend_height = blockchain.get_best_height() + 1;
+        let mut outpoints = vec![];
+        let mut missing_parents: Vec<Vec<u8>> = vec![];
+        let mut prev_outputs = vec![];
+        let mut input_value: i32 = 0;
+        for vin in tx.get_vin() {
+            let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+            if outpoints.contains(&outpoint) {
+                return Err(RejectReason::DuplicateInput);
+            }
+            if let Some(spender) = inner.spent_outputs.get(&outpoint) {
+                return Err(RejectReason::Conflict(spender.clone()));
+            }
+            outpoints.push(outpoint);
+            // 输入可以引用内存池中尚未打包的父交易
+            let output = if let Some(entry) = utxo_set.find_entry(vin.get_txid(), vin.get_vout()) {
+                if !entry.is_mature(spend_height) {
+                    return Err(RejectReason::ImmatureCoinbase);
+                }
+                Some(entry.get_output().clone())
+            } else if let Some(parent) = inner.txs.get(HEXLOWER.encode(vin.get_txid()).as_str()) {
+                parent.tx.get_vout().get(vin.get_vout()).cloned()
+            } else if utxo_set.has_unspent_outputs(vin.get_txid()) {
+                // 交易的其他输出仍未花费，说明引用的输出已被花费或不存在
+                None
+            } else {
+                if !missing_parents.iter().any(|txid| txid.eq(vin.get_txid())) {
+                    missing_parents.push(vin.get_txid().to_vec());
+                }
+                continue;
+            };
+            let output = output.ok_or(RejectReason::SpentInputs)?;
+            if !output.is_locked_with_key(hash_pub_key(vin.get_pub_key()).as_slice()) {
+                return Err(RejectReason::NotOwned);
+            }
+            input_value = input_value
+                .checked_add(output.get_value())
+                .ok_or(RejectReason::ValueOverflow)?;
+            prev_outputs.push(output);
+        }
+        if !missing_parents.is_empty() {
+            return Err(RejectReason::MissingInputs(missing_parents));
+        }
+        let output_value = tx.get_output_value().ok_or(RejectReason::ValueOverflow)?;
+        if output_value > input_value {
+            return Err(RejectReason::InsufficientInputs);
+        }
+        let fee = input_value - output_value;
+        let entry = MemoryPoolEntry::new(tx, fee, time);
+        // 最低转发手续费按每 1000 字节计算
+        if (fee as i64) * 1000 < (self.min_relay_fee as i64) * (entry.size as i64) {
+            return Err(RejectReason::InsufficientFee);
+        }
+        if !entry.tx.verify_with_outputs(prev_outputs.as_slice()) {
+            return Err(RejectReason::InvalidSignature);
+        }
+        if !inner.make_room(&entry) {
+            return Err(RejectReason::MempoolFull);
+        }
+        inner.insert(entry);
+        Ok(())
     }
 
     pub fn get(&self, txid_hex: &str) -> Option<Transaction> {
-        if let Some(tx) = self.inner.read().unwrap().get(txid_hex) {
-            return Some(tx.clone());
+        if let Some(entry) = self.inner.read().unwrap().txs.get(txid_hex) {
+            return Some(entry.tx.clone());
         }
         None
     }
 
+    /// 获取内存池中交易的手续费
+    pub fn get_fee(&self, txid_hex: &str) -> Option<i32> {
+        let inner = self.inner.read().unwrap();
+        inner.txs.get(txid_hex).map(|entry| entry.fee)
+    }
+
     pub fn remove(&self, txid_hex: &str) {
         let mut inner = self.inner.write().unwrap();
         inner.remove(txid_hex);
     }
 
+    /// 区块上链后，移除区块中的交易以及与区块中的交易花费了相同输出的交易（连同其后代交易）
+    pub fn remove_block_transactions(&self, block: &Block) {
+        let mut inner = self.inner.write().unwrap();
+        for tx in block.get_transactions() {
+            inner.remove(HEXLOWER.encode(tx.get_id()).as_str());
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in tx.get_vin() {
+                let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+                if let Some(spender) = inner.spent_outputs.get(&outpoint).cloned() {
+                    inner.remove_with_descendants(spender.as_str());
+                }
+            }
+        }
+    }
+
+    /// 获取所有交易，按费率从高到低排列
     pub fn get_all(&self) -> Vec<Transaction> {
         let inner = self.inner.read().unwrap();
         let mut txs = vec![];
-        for (_, v) in inner.iter() {
-            txs.push(v.clone());
+        for entry in inner.sorted_entries() {
+            txs.push(entry.tx.clone());
         }
         return txs;
     }
 
+    /// 为区块模板选择交易：按费率从高到低依次选取，交易总字节数不超过 max_size
+    /// 内存池中的父交易必须先于子交易被选取，子交易排在父交易之后
+    pub fn select_transactions(&self, max_size: usize) -> Vec<Transaction> {
+        let inner = self.inner.read().unwrap();
+        let entries = inner.sorted_entries();
+        let mut selected: HashSet<String> = HashSet::new();
+        let mut txs = vec![];
+        let mut size = 0;
+        loop {
+            let mut progress = false;
+            for entry in &entries {
+                let txid_hex = HEXLOWER.encode(entry.tx.get_id());
+                if selected.contains(&txid_hex) || size + entry.size > max_size {
+                    continue;
+                }
+                let waiting_parent = entry.tx.get_vin().iter().any(|vin| {
+                    let parent = HEXLOWER.encode(vin.get_txid());
+                    inner.txs.contains_key(parent.as_str()) && !selected.contains(&parent)
+                });
+                if waiting_parent {
+                    continue;
+                }
+                size += entry.size;
+                selected.insert(txid_hex);
+                txs.push(entry.tx.clone());
+                progress = true;
+            }
+            if !progress {
+                break;
+            }
+        }
+        txs
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().txs.len()
+    }
+
+    /// 移除在内存池中停留时间超过过期时间的交易，返回移除的交易数量
+    pub fn expire(&self, now: i64) -> usize {
+        let mut inner = self.inner.write().unwrap();
+        let expired: Vec<String> = inner
+            .txs
+            .iter()
+            .filter(|(_, entry)| entry.time + self.expiry <= now)
+            .map(|(txid_hex, _)| txid_hex.clone())
+            .collect();
+        for txid_hex in &expired {
+            inner.remove_with_descendants(txid_hex.as_str());
+        }
+        expired.len()
+    }
+
+    /// 将内存池中的交易保存到数据库，节点关闭时调用。过期的记录与当前交易在同一个批次中写入
+    pub fn save(&self, blockchain: &Blockchain) -> crate::Result<()> {
+        let storage = blockchain.get_storage();
+        let inner = self.inner.read().unwrap();
+        let mut batch = WriteBatch::new();
+        for item in storage.iter(MEMPOOL_TREE)? {
+            let (key, _) = item?;
+            if !inner
+                .txs
+                .contains_key(HEXLOWER.encode(key.as_slice()).as_str())
+            {
+                batch.remove(MEMPOOL_TREE, key);
+            }
+        }
+        for entry in inner.txs.values() {
+            let value = bincode::serialize(&(entry.time, &entry.tx))?;
+            batch.insert(MEMPOOL_TREE, entry.tx.get_id(), value);
+        }
+        storage.write_batch(batch)?;
+        storage.flush()
+    }
+
+    /// 从数据库加载节点上次关闭时保存的交易，交易按到达顺序重新校验，返回加入内存池的交易数量。
+    /// 无法读取或解析的记录会被跳过
+    pub fn load(&self, utxo_set: &UTXOSet) -> usize {
+        let storage = utxo_set.get_blockchain().get_storage();
+        let mut entries: Vec<(i64, Transaction)> = vec![];
+        let iter = match storage.iter(MEMPOOL_TREE) {
+            Ok(iter) => iter,
+            Err(e) => {
+                error!("Failed to read the saved memory pool: {}", e);
+                return 0;
+            }
+        };
+        for item in iter {
+            let entry = item
+                .and_then(|(_, v)| bincode::deserialize(v.as_slice()).map_err(crate::Error::from));
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(e) => error!("Skipped a corrupt memory pool entry: {}", e),
+            }
+        }
+        entries.sort_by_key(|(time, _)| *time);
+        let mut count = 0;
+        for (time, tx) in entries {
+            if self.accept_at(tx, time, utxo_set).is_ok() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// 内存池中交易的总字节数
+    pub fn size(&self) -> usize {
+        self.inner.read().unwrap().total_size
+    }
+}
+
+/// 孤儿交易，以及它到达的时间和缺失的父交易
+struct OrphanEntry {
+    tx: Transaction,
+    time: i64,
+    missing_parents: Vec<String>,
+}
+
+#[derive(Default)]
+struct OrphanPoolInner {
+    txs: HashMap<String, OrphanEntry>, // K -> txid_hex, V -> OrphanEntry
+    by_parent: HashMap<String, Vec<String>>, // K -> 缺失的父交易 txid_hex, V -> 等待该父交易的 txid_hex
+}
+
+impl OrphanPoolInner {
+    fn remove(&mut self, txid_hex: &str) -> Option<OrphanEntry> {
+        let entry = self.txs.remove(txid_hex)?;
+        for parent in &entry.missing_parents {
+            if let Some(children) = self.by_parent.get_mut(parent.as_str()) {
+                children.retain(|child| child.ne(txid_hex));
+                if children.is_empty() {
+                    self.by_parent.remove(parent.as_str());
+                }
+            }
+        }
+        Some(entry)
+    }
+}
+
+/// 孤儿交易池，保存父交易还未到达的交易，父交易被接受后重新处理这些交易
+#[derive(Default)]
+pub struct OrphanPool {
+    inner: RwLock<OrphanPoolInner>,
+}
+
+impl OrphanPool {
+    pub fn new() -> OrphanPool {
+        OrphanPool {
+            inner: RwLock::new(OrphanPoolInner {
+                txs: HashMap::new(),
+                by_parent: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn contains(&self, txid_hex: &str) -> bool {
+        self.inner.read().unwrap().txs.contains_key(txid_hex)
+    }
+
+    /// 加入孤儿交易，交易过大时拒绝并返回 false，孤儿交易池已满时驱逐最早到达的交易
+    pub fn add(&self, tx: Transaction, missing_parents: &[Vec<u8>], now: i64) -> bool {
+        if tx.serialize().len() > MAX_ORPHAN_TX_SIZE {
+            return false;
+        }
+        let mut inner = self.inner.write().unwrap();
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        if inner.txs.contains_key(txid_hex.as_str()) {
+            return true;
+        }
+        while inner.txs.len() >= MAX_ORPHAN_TRANSACTIONS {
+            let oldest = inner
+                .txs
+                .iter()
+                .min_by_key(|(_, entry)| entry.time)
+                .map(|(txid_hex, _)| txid_hex.clone())
+                .unwrap();
+            inner.remove(oldest.as_str());
+        }
+        let missing_parents: Vec<String> = missing_parents
+            .iter()
+            .map(|txid| HEXLOWER.encode(txid))
+            .collect();
+        for parent in &missing_parents {
+            inner
+                .by_parent
+                .entry(parent.clone())
+                .or_default()
+                .push(txid_hex.clone());
+        }
+        inner.txs.insert(
+            txid_hex,
+            OrphanEntry {
+                tx,
+                time: now,
+                missing_parents,
+            },
+        );
+        true
+    }
+
+    /// 取出等待指定父交易的孤儿交易
+    pub fn take_children(&self, parent_txid: &[u8]) -> Vec<Transaction> {
+        let mut inner = self.inner.write().unwrap();
+        let children = inner
+            .by_parent
+            .get(HEXLOWER.encode(parent_txid).as_str())
+            .cloned()
+            .unwrap_or_default();
+        children
+            .iter()
+            .filter_map(|txid_hex| inner.remove(txid_hex.as_str()))
+            .map(|entry| entry.tx)
+            .collect()
+    }
+
+    /// 移除过期的孤儿交易，返回移除的交易数量
+    pub fn expire(&self, now: i64) -> usize {
+        let mut inner = self.inner.write().unwrap();
+        let expired: Vec<String> = inner
+            .txs
+            .iter()
+            .filter(|(_, entry)| entry.time + ORPHAN_EXPIRY <= now)
+            .map(|(txid_hex, _)| txid_hex.clone())
+            .collect();
+        for txid_hex in &expired {
+            inner.remove(txid_hex.as_str());
+        }
+        expired.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().txs.is_empty()
+    }
+}
+
+/// 孤儿区块池，保存父区块还未到达的区块，父区块上链后再连接这些区块 ( K -> block_hash, V -> (Block, 到达时间) )
+#[derive(Default)]
+pub struct OrphanBlockPool {
+    inner: RwLock<HashMap<String, (Block, i64)>>,
+}
+
+impl OrphanBlockPool {
+    pub fn new() -> OrphanBlockPool {
+        OrphanBlockPool {
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn contains(&self, block_hash: &str) -> bool {
+        self.inner.read().unwrap().contains_key(block_hash)
+    }
+
+    /// 校验区块本身及工作量证明后加入孤儿区块池，孤儿区块池已满时驱逐最早到达的区块
+    pub fn add(&self, block: Block, now: i64) -> crate::Result<()> {
+        block.validate()?;
+        let mut inner = self.inner.write().unwrap();
+        if inner.contains_key(block.get_hash()) {
+            return Ok(());
+        }
+        while inner.len() >= MAX_ORPHAN_BLOCKS {
+            let oldest = inner
+                .iter()
+                .min_by_key(|(_, (_, time))| *time)
+                .map(|(block_hash, _)| block_hash.clone())
+                .unwrap();
+            inner.remove(oldest.as_str());
+        }
+        inner.insert(String::from(block.get_hash()), (block, now));
+        Ok(())
+    }
+
+    /// 沿着孤儿区块向上查找，返回第一个缺失的祖先区块的哈希
+    pub fn get_missing_ancestor(&self, block_hash: &str) -> Option<String> {
+        let inner = self.inner.read().unwrap();
+        let mut block = &inner.get(block_hash)?.0;
+        while let Some((parent, _)) = inner.get(block.get_pre_block_hash().as_str()) {
+            block = parent;
+        }
+        Some(block.get_pre_block_hash())
+    }
+
+    /// 取出父区块为 parent_hash 的孤儿区块
+    pub fn take_children(&self, parent_hash: &str) -> Vec<Block> {
+        let mut inner = self.inner.write().unwrap();
+        let children: Vec<String> = inner
+            .iter()
+            .filter(|(_, (block, _))| block.get_pre_block_hash().eq(parent_hash))
+            .map(|(block_hash, _)| block_hash.clone())
+            .collect();
+        children
+            .iter()
+            .filter_map(|block_hash| inner.remove(block_hash.as_str()))
+            .map(|(block, _)| block)
+            .collect()
+    }
+
     pub fn len(&self) -> usize {
         self.inner.read().unwrap().len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().is_empty()
+    }
 }
 
 /// 传输中的块, 用于来跟踪已下载的块, 这能够实现从不同的节点下载块.
@@ -96,14 +753,19 @@ impl BlockInTransit {
 
 #[cfg(test)]
 mod tests {
-    use super::{BlockInTransit, MemoryPool};
-    use crate::Transaction;
+    use super::MEMPOOL_TREE;
+    use super::{
+        BlockInTransit, MemoryPool, MemoryPoolEntry, OrphanBlockPool, OrphanPool, ORPHAN_EXPIRY,
+    };
+    use crate::proof_of_work::initial_bits;
+    use crate::{Block, Blockchain, MemoryStorage, Transaction, UTXOSet};
     use data_encoding::HEXLOWER;
+    use std::sync::Arc;
 
     #[test]
     fn test_memory_pool() {
         let pool = MemoryPool::new();
-        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
         let txid_hex = HEXLOWER.encode(tx.get_id());
         pool.add(tx);
         let option = pool.get(txid_hex.as_str());
@@ -114,6 +776,138 @@ mod tests {
         assert!(option.is_none());
     }
 
+    #[test]
+    fn test_save_and_load() {
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let blockchain =
+            Blockchain::create_with_storage(Arc::new(MemoryStorage::new()), address).unwrap();
+        let storage = blockchain.get_storage();
+        storage.insert(MEMPOOL_TREE, b"stale", b"stale").unwrap();
+
+        let pool = MemoryPool::new();
+        let tx = Transaction::new_coinbase_tx(address, 1, 0);
+        pool.add(tx.clone());
+        pool.save(&blockchain).unwrap();
+        // 上次保存的记录被移除，只保留当前内存池中的交易
+        let keys: Vec<Vec<u8>> = storage
+            .iter(MEMPOOL_TREE)
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![tx.get_id().to_vec()]);
+
+        // 损坏的记录被跳过，coinbase 交易无法通过校验
+        storage
+            .insert(MEMPOOL_TREE, b"corrupt", b"corrupt")
+            .unwrap();
+        let utxo_set = UTXOSet::new(blockchain);
+        let loaded = MemoryPool::new();
+        assert_eq!(loaded.load(&utxo_set), 0);
+        assert_eq!(loaded.len(), 0);
+    }
+
+    #[test]
+    fn test_fee_rate_order_and_eviction() {
+        let entry = |fee| {
+            let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+            MemoryPoolEntry::new(tx, fee, 0)
+        };
+        let (low, high, mid, lowest) = (entry(1), entry(5), entry(3), entry(0));
+        let size = low.size;
+        let pool = MemoryPool::with_limits(size * 2, 0, 60);
+        pool.inner.write().unwrap().insert(low.clone());
+        pool.inner.write().unwrap().insert(high.clone());
+        assert_eq!(pool.size(), size * 2);
+
+        // 按费率从高到低排列
+        let txs = pool.get_all();
+        assert_eq!(txs[0].get_id(), high.tx.get_id());
+        assert_eq!(txs[1].get_id(), low.tx.get_id());
+        let txs = pool.select_transactions(size);
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].get_id(), high.tx.get_id());
+
+        // 内存池已满，驱逐费率最低的交易
+        let mut inner = pool.inner.write().unwrap();
+        assert!(inner.make_room(&mid));
+        inner.insert(mid.clone());
+        assert!(!inner
+            .txs
+            .contains_key(HEXLOWER.encode(low.tx.get_id()).as_str()));
+        assert!(!inner.make_room(&lowest));
+        assert_eq!(inner.total_size, size * 2);
+    }
+
+    #[test]
+    fn test_memory_pool_expiry() {
+        let pool = MemoryPool::with_limits(usize::MAX, 0, 60);
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        pool.add(tx);
+        let now = crate::current_timestamp();
+        assert_eq!(pool.expire(now), 0);
+        assert_eq!(pool.expire(now + 60 * 1000), 1);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn test_orphan_pool() {
+        let pool = OrphanPool::new();
+        let parent = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        let child = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        let child_hex = HEXLOWER.encode(child.get_id());
+        assert!(pool.add(child.clone(), &[parent.get_id_bytes()], 0));
+        assert!(pool.contains(child_hex.as_str()));
+
+        // 父交易到达后取出子交易
+        let children = pool.take_children(parent.get_id());
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].get_id(), child.get_id());
+        assert_eq!(pool.len(), 0);
+
+        assert!(pool.add(child, &[parent.get_id_bytes()], 0));
+        assert_eq!(pool.expire(ORPHAN_EXPIRY - 1), 0);
+        assert_eq!(pool.expire(ORPHAN_EXPIRY), 1);
+        assert!(pool.take_children(parent.get_id()).is_empty());
+    }
+
+    #[test]
+    fn test_orphan_block_pool() {
+        let new_block = |pre_block_hash: &str, height| {
+            let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", height, 0);
+            Block::new_block(String::from(pre_block_hash), &[tx], height, initial_bits())
+        };
+        let missing_hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let block1 = new_block(missing_hash, 1);
+        let block2 = new_block(block1.get_hash(), 2);
+
+        let pool = OrphanBlockPool::new();
+        // 无效区块不会进入孤儿区块池
+        assert!(pool
+            .add(
+                Block::new_block(String::from(missing_hash), &[], 1, initial_bits()),
+                0
+            )
+            .is_err());
+        assert!(pool.is_empty());
+        pool.add(block2.clone(), 0).unwrap();
+        assert_eq!(
+            pool.get_missing_ancestor(block2.get_hash()).unwrap(),
+            block1.get_hash()
+        );
+        // 沿着孤儿区块找到最早缺失的祖先区块
+        pool.add(block1.clone(), 0).unwrap();
+        assert_eq!(
+            pool.get_missing_ancestor(block2.get_hash()).unwrap(),
+            missing_hash
+        );
+
+        let children = pool.take_children(missing_hash);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].get_hash(), block1.get_hash());
+        assert_eq!(pool.take_children(block1.get_hash()).len(), 1);
+        assert_eq!(pool.len(), 0);
+    }
+
     #[test]
     fn test_blocks_in_transit() {
         let mut block_hashs = vec![];
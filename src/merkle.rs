@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+
+/// 默克尔树，叶子节点为交易ID，奇数个节点时复制最后一个节点参与计算
+pub struct MerkleTree {
+    levels: Vec<Vec<Vec<u8>>>, // 每一层的节点哈希，第一层为叶子节点
+}
+
+impl MerkleTree {
+    /// 使用交易ID构建默克尔树
+    pub fn new(leaves: &[Vec<u8>]) -> MerkleTree {
+        let mut levels = vec![leaves.to_vec()];
+        loop {
+            let level = levels.last().unwrap();
+            if level.len() <= 1 {
+                break;
+            }
+            let mut parents = vec![];
+            for pair in level.chunks(2) {
+                let right = pair.get(1).unwrap_or(&pair[0]);
+                parents.push(hash_nodes(pair[0].as_slice(), right.as_slice()));
+            }
+            levels.push(parents);
+        }
+        MerkleTree { levels }
+    }
+
+    /// 默克尔根，没有叶子节点时为空数据的哈希
+    pub fn root(&self) -> Vec<u8> {
+        match self.levels.last().unwrap().first() {
+            Some(root) => root.clone(),
+            None => crate::sha256_digest(&[]),
+        }
+    }
+
+    /// 生成指定叶子节点的默克尔证明
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        let leaf = self.levels[0].get(index)?;
+        let mut branch = vec![];
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+            branch.push(sibling.clone());
+            position /= 2;
+        }
+        Some(MerkleProof {
+            txid: leaf.clone(),
+            index,
+            branch,
+        })
+    }
+}
+
+/// 默克尔证明：交易ID、交易在区块中的位置以及从叶子到根路径上的兄弟节点
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    txid: Vec<u8>,
+    index: usize,
+    branch: Vec<Vec<u8>>,
+}
+
+impl MerkleProof {
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    /// 沿着证明路径计算默克尔根
+    pub fn compute_root(&self) -> Vec<u8> {
+        let mut hash = self.txid.clone();
+        let mut position = self.index;
+        for sibling in &self.branch {
+            if position & 1 == 0 {
+                hash = hash_nodes(hash.as_slice(), sibling.as_slice());
+            } else {
+                hash = hash_nodes(sibling.as_slice(), hash.as_slice());
+            }
+            position /= 2;
+        }
+        hash
+    }
+
+    /// 验证交易包含在默克尔根为 root、共有 tx_count 笔交易的区块中
+    /// 证明路径的长度必须等于默克尔树的深度，否则中间节点也可以被当作交易ID证明
+    pub fn verify(&self, root: &[u8], tx_count: usize) -> bool {
+        if self.index >= tx_count || self.branch.len() != tree_depth(tx_count) {
+            return false;
+        }
+        self.compute_root().eq(root)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap().to_vec()
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> MerkleProof {
+        Self::try_deserialize(bytes).unwrap()
+    }
+
+    /// 从不可信的字节数组（如用户输入）反序列化
+    pub fn try_deserialize(bytes: &[u8]) -> crate::Result<MerkleProof> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// 有 leaf_count 个叶子节点的默克尔树从叶子到根的层数
+fn tree_depth(leaf_count: usize) -> usize {
+    let mut depth = 0;
+    let mut width = leaf_count;
+    while width > 1 {
+        width = width.div_ceil(2);
+        depth += 1;
+    }
+    depth
+}
+
+/// 计算父节点哈希
+fn hash_nodes(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut data = left.to_vec();
+    data.extend(right);
+    crate::sha256_digest(data.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MerkleProof, MerkleTree};
+
+    fn leaves(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| crate::sha256_digest(&[i])).collect()
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let txids = leaves(3);
+        let tree = MerkleTree::new(&txids);
+        let left = super::hash_nodes(txids[0].as_slice(), txids[1].as_slice());
+        let right = super::hash_nodes(txids[2].as_slice(), txids[2].as_slice());
+        assert_eq!(
+            tree.root(),
+            super::hash_nodes(left.as_slice(), right.as_slice())
+        );
+
+        // 只有一个交易时，默克尔根就是交易ID
+        let tree = MerkleTree::new(&txids[..1]);
+        assert_eq!(tree.root(), txids[0]);
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let txids = leaves(5);
+        let tree = MerkleTree::new(&txids);
+        for index in 0..txids.len() {
+            let proof = tree.proof(index).unwrap();
+            assert_eq!(proof.get_txid(), txids[index].as_slice());
+            assert!(proof.verify(tree.root().as_slice(), txids.len()));
+        }
+        assert!(tree.proof(txids.len()).is_none());
+
+        let other = MerkleTree::new(&leaves(4));
+        assert!(!tree
+            .proof(0)
+            .unwrap()
+            .verify(other.root().as_slice(), txids.len()));
+    }
+
+    #[test]
+    fn test_merkle_proof_depth() {
+        let txids = leaves(4);
+        let tree = MerkleTree::new(&txids);
+        // 中间节点不能被当作交易ID证明
+        let interior = MerkleProof {
+            txid: tree.levels[1][0].clone(),
+            index: 0,
+            branch: vec![tree.levels[1][1].clone()],
+        };
+        assert_eq!(interior.compute_root(), tree.root());
+        assert!(!interior.verify(tree.root().as_slice(), txids.len()));
+        // 交易位置不能超出区块中的交易数量
+        let proof = tree.proof(3).unwrap();
+        assert!(proof.verify(tree.root().as_slice(), 4));
+        assert!(!proof.verify(tree.root().as_slice(), 3));
+    }
+}
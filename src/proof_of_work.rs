@@ -1,37 +1,43 @@
-use crate::Block;
+use crate::BlockHeader;
 use data_encoding::HEXLOWER;
 use num_bigint::{BigInt, Sign};
 use std::borrow::Borrow;
 use std::ops::ShlAssign;
 
 pub struct ProofOfWork {
-    block: Block,
+    header: BlockHeader,
     target: BigInt,
 }
 
-/// 难度值，这里表示哈希的前20位必须是0
+/// 初始难度值，这里表示哈希的前8位必须是0，同时也是允许的最低难度
 const TARGET_BITS: i32 = 8;
 /// 限制 nonce 避免整型溢出
 const MAX_NONCE: i64 = i64::MAX;
+/// 每隔多少个区块调整一次难度
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: usize = 10;
+/// 期望的出块间隔，单位：ms
+pub const TARGET_BLOCK_SPACING: i64 = 10 * 1000;
+/// 单次难度调整的最大倍数
+const MAX_ADJUSTMENT_FACTOR: i64 = 4;
 
 impl ProofOfWork {
-    pub fn new_proof_of_work(block: Block) -> ProofOfWork {
-        let mut target = BigInt::from(1);
-        // target 等于 1 左移 256 - TARGET_BITS 位
-        target.shl_assign(256 - TARGET_BITS);
-        ProofOfWork { block, target }
+    pub fn new_proof_of_work(header: BlockHeader) -> ProofOfWork {
+        let target = bits_to_target(header.get_bits());
+        ProofOfWork { header, target }
     }
 
     /// 工作量证明用到的数据
     fn prepare_data(&self, nonce: i64) -> Vec<u8> {
-        let pre_block_hash = self.block.get_pre_block_hash();
-        let transactions_hash = self.block.hash_transactions();
-        let timestamp = self.block.get_timestamp();
+        let pre_block_hash = self.header.get_pre_block_hash();
+        let transactions_hash = self.header.get_merkle_root();
+        let timestamp = self.header.get_timestamp();
         let mut data_bytes = vec![];
+        data_bytes.extend(self.header.get_version().to_be_bytes());
         data_bytes.extend(pre_block_hash.as_bytes());
         data_bytes.extend(transactions_hash);
         data_bytes.extend(timestamp.to_be_bytes());
-        data_bytes.extend(TARGET_BITS.to_be_bytes());
+        data_bytes.extend(self.header.get_bits().to_be_bytes());
+        data_bytes.extend(self.header.get_height().to_be_bytes());
         data_bytes.extend(nonce.to_be_bytes());
         return data_bytes;
     }
@@ -47,10 +53,8 @@ impl ProofOfWork {
             let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
 
             // 1.在比特币中，当一个块被挖出来以后，“target bits” 代表了区块头里存储的难度，也就是开头有多少个 0。
-            // 2.这里的 20 指的是算出来的哈希前 20 位必须是 0，如果用 16 进制表示，就是前 5 位必须是 0，这一点从
-            //   最后的输出可以看出来。
-            //   例如：target 16进制输出是 0000100000000000000000000000000000000000000000000000000000000000
-            //   目前我们并不会实现一个动态调整目标的算法，所以将难度定义为一个全局的常量即可。
+            // 2.目标值由区块头中压缩格式的难度 bits 还原得到，创世块使用最低难度，即哈希的前 TARGET_BITS 位必须是 0。
+            //   之后每隔 DIFFICULTY_ADJUSTMENT_INTERVAL 个区块，根据上一个周期的出块时间重新计算难度（见 retarget_bits）。
             // 3.将哈希与目标数 target 进行比较：先把哈希转换成一个大整数，然后检测它是否小于目标，小就是有效的，反之无效。
             if hash_int.lt(self.target.borrow()) {
                 println!("{}", HEXLOWER.encode(hash.as_slice()));
@@ -62,11 +66,94 @@ impl ProofOfWork {
         println!();
         return (nonce, HEXLOWER.encode(hash.as_slice()));
     }
+
+    /// 校验区块头中记录的 nonce 和区块哈希：使用 nonce 重新计算哈希，要求与区块哈希一致，并且小于目标值
+    pub fn validate(&self, block_hash: &str) -> bool {
+        let data = self.prepare_data(self.header.get_nonce());
+        let hash = crate::sha256_digest(data.as_slice());
+        if HEXLOWER.encode(hash.as_slice()).ne(block_hash) {
+            return false;
+        }
+        if self.target.gt(&pow_limit()) {
+            return false;
+        }
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+        hash_int.lt(self.target.borrow())
+    }
+}
+
+/// 允许的最大目标值（最低难度）
+pub fn pow_limit() -> BigInt {
+    let mut target = BigInt::from(1);
+    // target 等于 1 左移 256 - TARGET_BITS 位
+    target.shl_assign(256 - TARGET_BITS);
+    target
+}
+
+/// 创世块使用的难度
+pub fn initial_bits() -> u32 {
+    target_to_bits(&pow_limit())
+}
+
+/// 将区块头中压缩格式的难度（与比特币的 nBits 相同）还原为目标值
+pub fn bits_to_target(bits: u32) -> BigInt {
+    let exponent = bits >> 24;
+    let mantissa = BigInt::from(bits & 0x007f_ffff);
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent))
+    } else {
+        mantissa << (8 * (exponent - 3))
+    }
+}
+
+/// 将目标值压缩为区块头中保存的难度：最高字节为目标值的字节数，低 3 字节为目标值的最高有效位
+pub fn target_to_bits(target: &BigInt) -> u32 {
+    let (_, bytes) = target.to_bytes_be();
+    let mut size = bytes.len() as u32;
+    let mut compact = 0u32;
+    for byte in bytes.iter().take(3) {
+        compact = (compact << 8) | *byte as u32;
+    }
+    if size < 3 {
+        compact <<= 8 * (3 - size);
+    }
+    // 最高位是符号位，需要额外占用一个字节
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size << 24)
+}
+
+/// 计算一个区块的工作量，即找到满足目标值的哈希平均需要尝试的次数：2^256 / (target + 1)
+pub fn block_work(bits: u32) -> BigInt {
+    let mut numerator = BigInt::from(1);
+    numerator.shl_assign(256);
+    numerator / (bits_to_target(bits) + 1)
+}
+
+/// 根据上一个难度周期实际花费的时间计算新的难度，调整倍数限制在 [1/4, 4] 之间
+pub fn retarget_bits(bits: u32, actual_timespan: i64) -> u32 {
+    let expected_timespan = TARGET_BLOCK_SPACING * (DIFFICULTY_ADJUSTMENT_INTERVAL as i64 - 1);
+    let actual_timespan = actual_timespan.clamp(
+        expected_timespan / MAX_ADJUSTMENT_FACTOR,
+        expected_timespan * MAX_ADJUSTMENT_FACTOR,
+    );
+    let mut target = bits_to_target(bits) * actual_timespan / expected_timespan;
+    let limit = pow_limit();
+    if target.gt(&limit) {
+        target = limit;
+    }
+    target_to_bits(&target)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::TARGET_BITS;
+    use super::{
+        bits_to_target, block_work, initial_bits, pow_limit, retarget_bits, target_to_bits,
+        ProofOfWork, DIFFICULTY_ADJUSTMENT_INTERVAL, TARGET_BITS, TARGET_BLOCK_SPACING,
+    };
+    use crate::{Block, Transaction};
     use data_encoding::HEXLOWER;
     use num_bigint::BigInt;
     use std::ops::ShlAssign;
@@ -93,4 +180,44 @@ mod tests {
         let target_hex = HEXLOWER.encode(vec.as_slice());
         println!("{}", target_hex) // output: 100000000000000000000000000000000000000000000000000000000000
     }
+
+    #[test]
+    fn test_compact_bits() {
+        let bits = initial_bits();
+        assert_eq!(bits, 0x2001_0000);
+        assert_eq!(bits_to_target(bits), pow_limit());
+
+        let target = BigInt::from(0x0012_3456_7800u64);
+        assert_eq!(target_to_bits(&target), 0x0512_3456);
+    }
+
+    #[test]
+    fn test_retarget_bits() {
+        let bits = target_to_bits(&(pow_limit() >> 1));
+        let expected_timespan = TARGET_BLOCK_SPACING * (DIFFICULTY_ADJUSTMENT_INTERVAL as i64 - 1);
+        // 出块速度正好符合预期，难度不变
+        assert_eq!(retarget_bits(bits, expected_timespan), bits);
+        // 出块过快，目标值最多缩小为原来的 1/4
+        let faster = retarget_bits(bits, 1);
+        assert_eq!(bits_to_target(faster), bits_to_target(bits) / 4);
+        // 出块过慢，目标值不能超过最低难度
+        let slower = retarget_bits(bits, expected_timespan * 100);
+        assert_eq!(bits_to_target(slower), pow_limit());
+    }
+
+    #[test]
+    fn test_block_work() {
+        let easy = initial_bits();
+        let hard = target_to_bits(&(pow_limit() >> 2));
+        assert_eq!(block_work(easy), BigInt::from(255));
+        assert!(block_work(hard) > block_work(easy));
+    }
+
+    #[test]
+    fn test_validate() {
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
+        let block = Block::new_block(String::from("None"), &vec![tx], 0, initial_bits());
+        let pow = ProofOfWork::new_proof_of_work(block.get_header().clone());
+        assert!(pow.validate(block.get_hash()));
+    }
 }
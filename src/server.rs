@@ -1,5 +1,6 @@
 use crate::{
-    Block, BlockInTransit, Blockchain, MemoryPool, Nodes, Transaction, UTXOSet, GLOBAL_CONFIG,
+    Block, BlockInTransit, Blockchain, MemoryPool, Nodes, OrphanBlockPool, OrphanPool,
+    RejectReason, Transaction, UTXOSet, GLOBAL_CONFIG, MAX_BLOCK_SIZE,
 };
 use data_encoding::HEXLOWER;
 use log::{error, info};
@@ -9,6 +10,7 @@ use serde_json::Deserializer;
 use std::error::Error;
 use std::io::{BufReader, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process;
 use std::thread;
 use std::time::Duration;
 
@@ -32,9 +34,18 @@ static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
 /// 交易内存池
 static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
 
+/// 孤儿交易池
+static GLOBAL_ORPHAN_POOL: Lazy<OrphanPool> = Lazy::new(OrphanPool::new);
+
+/// 孤儿区块池
+static GLOBAL_ORPHAN_BLOCKS: Lazy<OrphanBlockPool> = Lazy::new(OrphanBlockPool::new);
+
 /// 传输中的Block, 用于来跟踪已下载的块, 这能够实现从不同的节点下载块
 static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
 
+/// 区块模板为区块头和 coinbase 交易预留的字节数
+const BLOCK_RESERVED_SIZE: usize = 1000;
+
 /// 网络写超时
 const TCP_WRITE_TIMEOUT: u64 = 1000;
 
@@ -50,6 +61,24 @@ impl Server {
     pub fn run(&self, addr: &str) {
         let listener = TcpListener::bind(addr).unwrap();
 
+        // 恢复节点上次关闭时保存的内存池交易
+        let utxo_set = UTXOSet::new(self.blockchain.clone());
+        let count = GLOBAL_MEMORY_POOL.load(&utxo_set);
+        info!("Loaded {} transactions into the memory pool", count);
+        // 节点关闭时（Ctrl-C 或服务管理器发送的 SIGTERM、SIGHUP）保存内存池
+        let blockchain = self.blockchain.clone();
+        ctrlc::set_handler(move || {
+            match GLOBAL_MEMORY_POOL.save(&blockchain) {
+                Ok(()) => info!(
+                    "Saved {} transactions of the memory pool",
+                    GLOBAL_MEMORY_POOL.len()
+                ),
+                Err(e) => error!("Failed to save the memory pool: {}", e),
+            }
+            process::exit(0);
+        })
+        .expect("unable to set the shutdown handler");
+
         // 发送 version 握手
         if addr.eq(CENTERAL_NODE) == false {
             let best_height = self.blockchain.get_best_height();
@@ -110,10 +139,9 @@ pub enum Package {
 }
 
 fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     send_data(
-        socket_addr,
+        addr,
         Package::GetData {
             addr_from: node_addr,
             op_type,
@@ -123,10 +151,9 @@ fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
 }
 
 fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     send_data(
-        socket_addr,
+        addr,
         Package::Inv {
             addr_from: node_addr,
             op_type,
@@ -136,10 +163,9 @@ fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
 }
 
 fn send_block(addr: &str, block: &Block) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     send_data(
-        socket_addr,
+        addr,
         Package::Block {
             addr_from: node_addr,
             block: block.serialize(),
@@ -148,10 +174,9 @@ fn send_block(addr: &str, block: &Block) {
 }
 
 pub fn send_tx(addr: &str, tx: &Transaction) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     send_data(
-        socket_addr,
+        addr,
         Package::Tx {
             addr_from: node_addr,
             transaction: tx.serialize(),
@@ -160,10 +185,9 @@ pub fn send_tx(addr: &str, tx: &Transaction) {
 }
 
 fn send_version(addr: &str, height: usize) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     send_data(
-        socket_addr,
+        addr,
         Package::Version {
             addr_from: node_addr,
             version: NODE_VERSION,
@@ -173,16 +197,70 @@ fn send_version(addr: &str, height: usize) {
 }
 
 fn send_get_blocks(addr: &str) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     send_data(
-        socket_addr,
+        addr,
         Package::GetBlocks {
             addr_from: node_addr,
         },
     );
 }
 
+/// 校验交易并加入内存池，返回加入内存池的交易ID
+/// 缺少父交易的交易放入孤儿交易池并向对端请求父交易，交易被接受后重新处理等待它的孤儿交易
+fn process_transaction(blockchain: &Blockchain, addr_from: &str, tx: Transaction) -> Vec<Vec<u8>> {
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    let mut accepted = vec![];
+    let mut queue = vec![tx];
+    while let Some(tx) = queue.pop() {
+        let txid = tx.get_id_bytes();
+        let txid_hex = HEXLOWER.encode(txid.as_slice());
+        match GLOBAL_MEMORY_POOL.accept(tx.clone(), &utxo_set) {
+            Ok(()) => {
+                queue.extend(GLOBAL_ORPHAN_POOL.take_children(txid.as_slice()));
+                accepted.push(txid);
+            }
+            Err(RejectReason::MissingInputs(parents)) => {
+                if !GLOBAL_ORPHAN_POOL.add(tx, parents.as_slice(), crate::current_timestamp()) {
+                    error!("Rejected orphan transaction {}: it is too large", txid_hex);
+                    continue;
+                }
+                info!("Transaction {} is an orphan", txid_hex);
+                for parent in &parents {
+                    if !GLOBAL_ORPHAN_POOL.contains(HEXLOWER.encode(parent).as_str()) {
+                        send_get_data(addr_from, OpType::Tx, parent);
+                    }
+                }
+            }
+            Err(reason) => error!("Rejected transaction {}: {}", txid_hex, reason),
+        }
+    }
+    accepted
+}
+
+/// 校验区块并加入区块链，同时更新内存池
+fn connect_block(blockchain: &Blockchain, addr_from: &str, block: &Block) -> crate::Result<()> {
+    let displaced_txs = blockchain.add_block(block)?;
+    info!("Added block {}", block.get_hash());
+    // 从内存池中移除已打包的交易以及与之冲突的交易
+    GLOBAL_MEMORY_POOL.remove_block_transactions(block);
+    // 链重组后，旧分支中仍然有效的交易退回内存池
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    for tx in displaced_txs {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        if let Err(reason) = GLOBAL_MEMORY_POOL.accept(tx, &utxo_set) {
+            info!("Dropped displaced transaction {}: {}", txid_hex, reason);
+        }
+    }
+    // 区块中的交易可能是孤儿交易的父交易
+    for tx in block.get_transactions() {
+        for child in GLOBAL_ORPHAN_POOL.take_children(tx.get_id()) {
+            process_transaction(blockchain, addr_from, child);
+        }
+    }
+    Ok(())
+}
+
 fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
     let peer_addr = stream.peer_addr()?;
     let reader = BufReader::new(&stream);
@@ -192,9 +270,55 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
         info!("Receive request from {}: {:?}", peer_addr, pkg);
         match pkg {
             Package::Block { addr_from, block } => {
-                let block = Block::deserialize(block.as_slice());
-                blockchain.add_block(&block);
-                info!("Added block {}", block.get_hash());
+                let block = match Block::try_deserialize(block.as_slice()) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        error!("Rejected malformed block from {}: {}", addr_from, e);
+                        continue;
+                    }
+                };
+                let known = blockchain
+                    .get_block(block.get_hash_bytes().as_slice())?
+                    .is_some()
+                    || GLOBAL_ORPHAN_BLOCKS.contains(block.get_hash());
+                if !known
+                    && blockchain
+                        .get_block_header(block.get_pre_block_hash().as_bytes())
+                        .is_none()
+                {
+                    // 父区块未知，校验工作量证明后放入孤儿区块池，并向对端请求缺失的祖先区块
+                    let block_hash = String::from(block.get_hash());
+                    if let Err(e) = GLOBAL_ORPHAN_BLOCKS.add(block, crate::current_timestamp()) {
+                        error!("Rejected block {}: {}", block_hash, e);
+                        continue;
+                    }
+                    info!("Block {} is an orphan", block_hash);
+                    if let Some(ancestor) = GLOBAL_ORPHAN_BLOCKS.get_missing_ancestor(&block_hash) {
+                        send_get_data(addr_from.as_str(), OpType::Block, ancestor.as_bytes());
+                    }
+                } else if !known {
+                    // 校验区块，拒绝无效区块
+                    if let Err(e) = connect_block(&blockchain, addr_from.as_str(), &block) {
+                        error!("Rejected block {}: {}", block.get_hash(), e);
+                        GLOBAL_BLOCKS_IN_TRANSIT.clear();
+                        continue;
+                    }
+                    // 连接等待该区块的孤儿区块，无效孤儿区块的后代也一并丢弃
+                    let mut parents = vec![(String::from(block.get_hash()), true)];
+                    while let Some((parent_hash, parent_valid)) = parents.pop() {
+                        for child in GLOBAL_ORPHAN_BLOCKS.take_children(parent_hash.as_str()) {
+                            if !parent_valid {
+                                parents.push((String::from(child.get_hash()), false));
+                                continue;
+                            }
+                            let result = connect_block(&blockchain, addr_from.as_str(), &child);
+                            if let Err(e) = &result {
+                                error!("Rejected block {}: {}", child.get_hash(), e);
+                            }
+                            parents.push((String::from(child.get_hash()), result.is_ok()));
+                        }
+                    }
+                }
 
                 if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
                     // 继续下载区块
@@ -202,10 +326,6 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                     send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
                     // 从下载列表中移除
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
-                } else {
-                    // 区块全部下载后，再重建索引
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
                 }
             }
             Package::GetBlocks { addr_from } => {
@@ -218,7 +338,7 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 id,
             } => match op_type {
                 OpType::Block => {
-                    if let Some(block) = blockchain.get_block(id.as_slice()) {
+                    if let Some(block) = blockchain.get_block(id.as_slice())? {
                         send_block(addr_from.as_str(), &block);
                     }
                 }
@@ -238,21 +358,29 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 //  1. 当 version 消息检查到区块高度落后，会收到全量的 block hash 列表。
                 //  2. 矿工挖出新的区块后，会将新区块的 hash 广播给所有节点。
                 OpType::Block => {
+                    // 收到的哈希列表从最新区块开始，按从创世块开始的顺序下载，保证父区块先于子区块被校验
+                    let items: Vec<Vec<u8>> = items.into_iter().rev().collect();
                     // 初始启动才会触发，不可能有存量数据
                     GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
 
                     // 下载一个区块
-                    let block_hash = items.get(0).unwrap();
+                    let Some(block_hash) = items.first() else {
+                        continue;
+                    };
                     send_get_data(addr_from.as_str(), OpType::Block, block_hash);
                     // 从下载列表中移除
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
                 }
                 OpType::Tx => {
-                    let txid = items.get(0).unwrap();
+                    let Some(txid) = items.first() else {
+                        continue;
+                    };
                     let txid_hex = HEXLOWER.encode(txid);
 
-                    // 检查交易池，不包含交易则下载
-                    if GLOBAL_MEMORY_POOL.containes(txid_hex.as_str()) == false {
+                    // 检查交易池和孤儿交易池，不包含交易则下载
+                    if GLOBAL_MEMORY_POOL.containes(txid_hex.as_str()) == false
+                        && !GLOBAL_ORPHAN_POOL.contains(txid_hex.as_str())
+                    {
                         send_get_data(addr_from.as_str(), OpType::Tx, txid);
                     }
                 }
@@ -262,9 +390,24 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 transaction,
             } => {
                 // 记录交易到内存池
-                let tx = Transaction::deserialize(transaction.as_slice());
-                let txid = tx.get_id_bytes();
-                GLOBAL_MEMORY_POOL.add(tx);
+                let tx = match Transaction::try_deserialize(transaction.as_slice()) {
+                    Ok(tx) => tx,
+                    Err(e) => {
+                        error!("Rejected malformed transaction from {}: {}", addr_from, e);
+                        continue;
+                    }
+                };
+                // 移除过期的交易
+                let now = crate::current_timestamp();
+                let expired = GLOBAL_MEMORY_POOL.expire(now) + GLOBAL_ORPHAN_POOL.expire(now);
+                if expired > 0 {
+                    info!("Expired {} transactions from the memory pool", expired);
+                }
+                // 校验交易，拒绝无效或与内存池冲突的交易
+                let accepted = process_transaction(&blockchain, addr_from.as_str(), tx);
+                if accepted.is_empty() {
+                    continue;
+                }
 
                 let node_addr = GLOBAL_CONFIG.get_node_addr();
                 // 中心节点（广播交易）
@@ -277,28 +420,47 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                         if addr_from.eq(node.get_addr().as_str()) {
                             continue;
                         }
-                        send_inv(node.get_addr().as_str(), OpType::Tx, &vec![txid.clone()])
+                        for txid in &accepted {
+                            send_inv(node.get_addr().as_str(), OpType::Tx, &vec![txid.clone()])
+                        }
                     }
                 }
                 // 矿工节点（内存池中的交易到达一定数量，挖出新区块）
                 if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
                     // 挖矿奖励
                     let mining_address = GLOBAL_CONFIG.get_mining_addr().unwrap();
-                    let coinbase_tx = Transaction::new_coinbase_tx(mining_address.as_str());
-                    let mut txs = GLOBAL_MEMORY_POOL.get_all();
+                    // 按费率选取交易
+                    let mut txs = GLOBAL_MEMORY_POOL
+                        .select_transactions(MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE);
+                    let fees = txs
+                        .iter()
+                        .filter_map(|tx| {
+                            GLOBAL_MEMORY_POOL.get_fee(HEXLOWER.encode(tx.get_id()).as_str())
+                        })
+                        .try_fold(0i32, |sum, fee| sum.checked_add(fee));
+                    let Some(fees) = fees else {
+                        error!("Fees of the selected transactions overflow");
+                        continue;
+                    };
+                    let coinbase_tx = Transaction::new_coinbase_tx(
+                        mining_address.as_str(),
+                        blockchain.get_best_height() + 1,
+                        fees,
+                    );
                     txs.push(coinbase_tx);
 
-                    // 挖区块
-                    let new_block = blockchain.mine_block(&txs);
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
+                    // 挖区块，UTXO 集随区块一起更新
+                    let new_block = match blockchain.mine_block(&txs) {
+                        Ok(block) => block,
+                        Err(e) => {
+                            error!("Discarded the mined block: {}", e);
+                            continue;
+                        }
+                    };
                     info!("New block {} is mined!", new_block.get_hash());
 
                     // 从内存池中移除交易
-                    for tx in &txs {
-                        let txid_hex = HEXLOWER.encode(tx.get_id());
-                        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
-                    }
+                    GLOBAL_MEMORY_POOL.remove_block_transactions(&new_block);
                     // 广播新区块
                     let nodes = GLOBAL_NODES.get_nodes();
                     for node in &nodes {
@@ -338,13 +500,22 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
 }
 
 /// 统一发送请求
-fn send_data(addr: SocketAddr, pkg: Package) {
+/// 地址来自其他节点的消息，无法解析时记录错误并驱逐该节点
+fn send_data(addr: &str, pkg: Package) {
     info!("send package: {:?}", &pkg);
-    let stream = TcpStream::connect(addr);
+    let socket_addr: SocketAddr = match addr.parse() {
+        Ok(socket_addr) => socket_addr,
+        Err(e) => {
+            error!("The {} is not a valid address: {}", addr, e);
+            GLOBAL_NODES.evict_node(addr);
+            return;
+        }
+    };
+    let stream = TcpStream::connect(socket_addr);
     if stream.is_err() {
         error!("The {} is not valid", addr);
         // 驱逐不健康的 Node
-        GLOBAL_NODES.evict_node(addr.to_string().as_str());
+        GLOBAL_NODES.evict_node(addr);
         return;
     }
     let mut stream = stream.unwrap();
@@ -375,6 +546,12 @@ mod tests {
         send_get_data("127.0.0.1:2001", OpType::Tx, &txid);
     }
 
+    #[test]
+    fn test_send_to_invalid_address() {
+        // 其他节点发来的 addr_from 无法解析时不能 panic
+        send_get_data("not an address", OpType::Block, &[0u8; 32]);
+    }
+
     #[test]
     fn test_vec() {
         let a = vec![6, 1, 2, 3, 5, 4];
@@ -0,0 +1,304 @@
+use crate::{Error, Result};
+use sled::transaction::{TransactionError, TransactionResult};
+use sled::{Db, Transactional, Tree};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::RwLock;
+
+/// 遍历一棵树中按键排序的键值对
+pub type StorageIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// 区块链存储，数据按名称划分为多棵树（区块、区块头、UTXO 集等），每棵树是按键排序的键值存储
+pub trait Storage: Send + Sync {
+    /// 查询键对应的值
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// 写入键值对
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// 删除键值对
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<()>;
+
+    /// 按键的顺序遍历整棵树
+    fn iter(&self, tree: &str) -> Result<StorageIter<'_>>;
+
+    /// 按键的顺序遍历树中以 prefix 开头的键值对
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<StorageIter<'_>>;
+
+    /// 清空整棵树
+    fn clear(&self, tree: &str) -> Result<()>;
+
+    /// 原子地写入一批修改，要么全部生效，要么全部不生效
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// 将缓存的修改写入持久化存储
+    fn flush(&self) -> Result<()>;
+}
+
+/// 一批需要原子写入的修改，可以跨多棵树
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<(String, Vec<u8>, Option<Vec<u8>>)>, // (树, 键, 值)，值为 None 表示删除
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: vec![] }
+    }
+
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, tree: &str, key: K, value: V) {
+        self.ops.push((
+            String::from(tree),
+            key.as_ref().to_vec(),
+            Some(value.as_ref().to_vec()),
+        ));
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, tree: &str, key: K) {
+        self.ops
+            .push((String::from(tree), key.as_ref().to_vec(), None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// 基于 sled 的持久化存储，每棵树对应一个 sled tree
+pub struct SledStorage {
+    db: Db,
+}
+
+impl SledStorage {
+    /// 打开指定目录下的 sled 数据库，目录不存在时自动创建
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStorage> {
+        let db = sled::open(path)?;
+        Ok(SledStorage { db })
+    }
+
+    fn tree(&self, name: &str) -> Result<Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.tree(tree)?.get(key)?;
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let _ = self.tree(tree)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<()> {
+        let _ = self.tree(tree)?.remove(key)?;
+        Ok(())
+    }
+
+    fn iter(&self, tree: &str) -> Result<StorageIter<'_>> {
+        let iter = self.tree(tree)?.iter().map(|item| {
+            let (k, v) = item?;
+            Ok((k.to_vec(), v.to_vec()))
+        });
+        Ok(Box::new(iter))
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<StorageIter<'_>> {
+        let iter = self.tree(tree)?.scan_prefix(prefix).map(|item| {
+            let (k, v) = item?;
+            Ok((k.to_vec(), v.to_vec()))
+        });
+        Ok(Box::new(iter))
+    }
+
+    fn clear(&self, tree: &str) -> Result<()> {
+        Ok(self.tree(tree)?.clear()?)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        // 将修改涉及的树放到同一个 sled 事务中
+        let mut names: Vec<&str> = vec![];
+        let mut ops = vec![];
+        for (tree, key, value) in &batch.ops {
+            let idx = match names.iter().position(|name| name.eq(tree)) {
+                Some(idx) => idx,
+                None => {
+                    names.push(tree.as_str());
+                    names.len() - 1
+                }
+            };
+            ops.push((idx, key, value));
+        }
+        let trees = names
+            .iter()
+            .map(|name| self.tree(name))
+            .collect::<Result<Vec<Tree>>>()?;
+        let result: TransactionResult<(), ()> = trees.as_slice().transaction(|views| {
+            for (idx, key, value) in &ops {
+                match value {
+                    Some(value) => {
+                        let _ = views[*idx].insert(key.as_slice(), value.as_slice())?;
+                    }
+                    None => {
+                        let _ = views[*idx].remove(key.as_slice())?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Storage(e)) => Err(Error::Storage(e)),
+            Err(TransactionError::Abort(())) => unreachable!("the batch is never aborted"),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        let _ = self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// 内存中的一棵树
+type MemoryTree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// 内存存储，进程退出后数据丢失，用于测试或临时的区块链实例
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: RwLock<HashMap<String, MemoryTree>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            trees: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.read().unwrap();
+        Ok(trees.get(tree).and_then(|tree| tree.get(key).cloned()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        trees
+            .entry(String::from(tree))
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        if let Some(tree) = trees.get_mut(tree) {
+            tree.remove(key);
+        }
+        Ok(())
+    }
+
+    fn iter(&self, tree: &str) -> Result<StorageIter<'_>> {
+        // 复制一份快照，遍历期间不持有锁
+        let trees = self.trees.read().unwrap();
+        let items: Vec<(Vec<u8>, Vec<u8>)> = match trees.get(tree) {
+            Some(tree) => tree.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            None => vec![],
+        };
+        Ok(Box::new(items.into_iter().map(Ok)))
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<StorageIter<'_>> {
+        let trees = self.trees.read().unwrap();
+        let items: Vec<(Vec<u8>, Vec<u8>)> = match trees.get(tree) {
+            Some(tree) => tree
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            None => vec![],
+        };
+        Ok(Box::new(items.into_iter().map(Ok)))
+    }
+
+    fn clear(&self, tree: &str) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        trees.remove(tree);
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        for (tree, key, value) in batch.ops {
+            let tree = trees.entry(tree).or_default();
+            match value {
+                Some(value) => {
+                    tree.insert(key, value);
+                }
+                None => {
+                    tree.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryStorage, SledStorage, Storage, WriteBatch};
+
+    fn check_storage(storage: &dyn Storage) {
+        storage.insert("blocks", b"b", b"2").unwrap();
+        storage.insert("blocks", b"a", b"1").unwrap();
+        assert_eq!(storage.get("blocks", b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(storage.get("headers", b"a").unwrap(), None);
+
+        // 遍历按键排序
+        let keys: Vec<Vec<u8>> = storage
+            .iter("blocks")
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+
+        storage.insert("blocks", b"ab", b"3").unwrap();
+        let keys: Vec<Vec<u8>> = storage
+            .scan_prefix("blocks", b"a")
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"ab".to_vec()]);
+        storage.remove("blocks", b"ab").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.remove("blocks", b"a");
+        batch.insert("headers", b"a", b"3");
+        storage.write_batch(batch).unwrap();
+        assert_eq!(storage.get("blocks", b"a").unwrap(), None);
+        assert_eq!(storage.get("headers", b"a").unwrap(), Some(b"3".to_vec()));
+
+        storage.clear("blocks").unwrap();
+        assert_eq!(storage.iter("blocks").unwrap().count(), 0);
+        assert_eq!(storage.iter("headers").unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_storage(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_sled_storage() {
+        let path = crate::temp_data_dir();
+        check_storage(&SledStorage::open(&path).unwrap());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
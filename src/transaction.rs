@@ -1,11 +1,37 @@
 use crate::wallet::hash_pub_key;
-use crate::{base58_decode, wallet, Blockchain, UTXOSet, Wallets};
+use crate::{base58_decode, wallet, Blockchain, Error, Result, UTXOSet, Wallets, GLOBAL_CONFIG};
 use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-/// 挖矿奖励金
-const SUBSIDY: i32 = 10;
+/// 初始挖矿奖励金，之后每隔一个减半周期减半
+const INITIAL_SUBSIDY: i32 = 10;
+
+/// 计算指定高度区块的挖矿奖励
+pub fn get_block_subsidy(height: usize) -> i32 {
+    let halvings = height / GLOBAL_CONFIG.get_subsidy_halving_interval();
+    if halvings >= 32 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// 计算从创世块到指定高度按计划发行的货币总量
+pub fn get_scheduled_supply(height: usize) -> i64 {
+    let interval = GLOBAL_CONFIG.get_subsidy_halving_interval();
+    let mut supply = 0;
+    let mut start = 0;
+    while start <= height {
+        let subsidy = get_block_subsidy(start);
+        if subsidy == 0 {
+            break;
+        }
+        let end = std::cmp::min(start + interval - 1, height);
+        supply += subsidy as i64 * (end - start + 1) as i64;
+        start += interval;
+    }
+    supply
+}
 
 /// 交易输入
 #[derive(Clone, Default, Serialize, Deserialize)]
@@ -73,7 +99,7 @@ impl TXOutput {
     }
 
     fn lock(&mut self, address: &str) {
-        let payload = base58_decode(address);
+        let payload = base58_decode(address).expect("The address is valid");
         let pub_key_hash = payload[1..payload.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec();
         self.pub_key_hash = pub_key_hash;
     }
@@ -92,9 +118,9 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    /// 创建一个 coinbase 交易，该没有输入，只有一个输出
-    pub fn new_coinbase_tx(to: &str) -> Transaction {
-        let txout = TXOutput::new(SUBSIDY, to);
+    /// 创建一个 coinbase 交易，该没有输入，只有一个输出，矿工获得区块高度对应的挖矿奖励及区块中所有交易的手续费
+    pub fn new_coinbase_tx(to: &str, height: usize, fees: i32) -> Transaction {
+        let txout = TXOutput::new(get_block_subsidy(height).saturating_add(fees), to);
         let mut tx_input = TXInput::default();
         tx_input.signature = Uuid::new_v4().as_bytes().to_vec();
 
@@ -108,22 +134,55 @@ impl Transaction {
         return tx;
     }
 
-    /// 创建一笔 UTXO 的交易
+    /// 创建一笔 UTXO 的交易，输入总额减去输出总额即为支付给矿工的手续费
     pub fn new_utxo_transaction(
         from: &str,
         to: &str,
         amount: i32,
+        fee: i32,
         utxo_set: &UTXOSet,
     ) -> Transaction {
+        match Self::try_new_utxo_transaction(from, to, amount, fee, utxo_set) {
+            Ok(tx) => tx,
+            Err(e) => panic!("Error: {}", e),
+        }
+    }
+
+    /// 创建一笔 UTXO 的交易，钱包不存在、地址无效或余额不足时返回错误
+    pub fn try_new_utxo_transaction(
+        from: &str,
+        to: &str,
+        amount: i32,
+        fee: i32,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        let wallets = Wallets::try_new()?;
+        Self::new_utxo_transaction_with_wallets(&wallets, from, to, amount, fee, utxo_set)
+    }
+
+    /// 使用指定的钱包集合创建一笔 UTXO 的交易
+    pub fn new_utxo_transaction_with_wallets(
+        wallets: &Wallets,
+        from: &str,
+        to: &str,
+        amount: i32,
+        fee: i32,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        wallet::decode_address(to)?;
         // 1.查找钱包
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).expect("unable to found wallet");
+        let wallet = wallets
+            .get_wallet(from)
+            .ok_or_else(|| Error::WalletNotFound(String::from(from)))?;
         let public_key_hash = hash_pub_key(wallet.get_public_key());
-        // 2.找到足够的未花费输出
+        // 2.找到足够支付金额和手续费的未花费输出
         let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
-        if accumulated < amount {
-            panic!("Error: Not enough funds")
+            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount + fee);
+        if accumulated < amount + fee {
+            return Err(Error::InsufficientFunds {
+                required: amount + fee,
+                available: accumulated,
+            });
         }
         // 3.交易数据
         // 3.1.交易的输入
@@ -143,8 +202,8 @@ impl Transaction {
         // 3.2.交易的输出
         let mut outputs = vec![TXOutput::new(amount, to)];
         // 如果 UTXO 总数超过所需，则产生找零
-        if accumulated > amount {
-            outputs.push(TXOutput::new(accumulated - amount, from)) // to: 币收入
+        if accumulated > amount + fee {
+            outputs.push(TXOutput::new(accumulated - amount - fee, from)) // to: 币收入
         }
         // 4.生成交易
         let mut tx = Transaction {
@@ -155,8 +214,23 @@ impl Transaction {
         // 生成交易ID
         tx.id = tx.hash();
         // 5.交易中的 TXInput 签名
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8());
-        return tx;
+        let prev_outputs = tx
+            .vin
+            .iter()
+            .map(|vin| {
+                utxo_set
+                    .find_output(vin.get_txid(), vin.get_vout())
+                    .ok_or_else(|| {
+                        Error::InvalidTransaction(String::from(
+                            "unable to found the spendable output",
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<TXOutput>>>()?;
+        tx.sign(prev_outputs.as_slice(), wallet.get_pkcs8());
+        // 签名不依赖交易ID，签名后重新计算交易ID使其覆盖签名
+        tx.id = tx.hash();
+        Ok(tx)
     }
 
     /// 创建一个修剪后的交易副本
@@ -177,19 +251,13 @@ impl Transaction {
         }
     }
 
-    /// 对交易的每个输入进行签名
-    fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) {
+    /// 对交易的每个输入进行签名，prev_outputs[i] 为第 i 个输入引用的输出
+    fn sign(&mut self, prev_outputs: &[TXOutput], pkcs8: &[u8]) {
         let mut tx_copy = self.trimmed_copy();
 
         for (idx, vin) in self.vin.iter_mut().enumerate() {
-            // 查找输入引用的交易
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
-            }
-            let prev_tx = prev_tx_option.unwrap();
             tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
+            tx_copy.vin[idx].pub_key = prev_outputs[idx].pub_key_hash.clone();
             tx_copy.id = tx_copy.hash();
             tx_copy.vin[idx].pub_key = vec![];
 
@@ -199,20 +267,38 @@ impl Transaction {
         }
     }
 
-    /// 对交易的每个输入进行签名验证
+    /// 对交易的每个输入进行签名验证，找不到输入引用的交易时验证失败
     pub fn verify(&self, blockchain: &Blockchain) -> bool {
         if self.is_coinbase() {
             return true;
         }
+        let mut prev_outputs = vec![];
+        for vin in &self.vin {
+            let prev_output = blockchain
+                .find_transaction(vin.get_txid())
+                .ok()
+                .flatten()
+                .and_then(|prev_tx| prev_tx.vout.get(vin.vout).cloned());
+            match prev_output {
+                Some(output) => prev_outputs.push(output),
+                None => return false,
+            }
+        }
+        self.verify_with_outputs(prev_outputs.as_slice())
+    }
+
+    /// 使用输入引用的输出对交易的每个输入进行签名验证，prev_outputs[i] 为第 i 个输入引用的输出
+    pub fn verify_with_outputs(&self, prev_outputs: &[TXOutput]) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+        if prev_outputs.len() != self.vin.len() {
+            return false;
+        }
         let mut tx_copy = self.trimmed_copy();
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
-            }
-            let prev_tx = prev_tx_option.unwrap();
             tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
+            tx_copy.vin[idx].pub_key = prev_outputs[idx].pub_key_hash.clone();
             tx_copy.id = tx_copy.hash();
             tx_copy.vin[idx].pub_key = vec![];
 
@@ -229,13 +315,25 @@ impl Transaction {
         true
     }
 
+    /// 计算交易所有输出的总额，溢出时返回 None
+    pub fn get_output_value(&self) -> Option<i32> {
+        self.vout
+            .iter()
+            .try_fold(0i32, |sum, out| sum.checked_add(out.get_value()))
+    }
+
     /// 判断是否是 coinbase 交易
     pub fn is_coinbase(&self) -> bool {
         return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
     }
 
+    /// 校验交易ID与交易内容一致
+    pub fn is_id_valid(&self) -> bool {
+        self.id.eq(&self.hash())
+    }
+
     /// 生成交易的哈希
-    fn hash(&mut self) -> Vec<u8> {
+    fn hash(&self) -> Vec<u8> {
         let tx_copy = Transaction {
             id: vec![],
             vin: self.vin.clone(),
@@ -265,43 +363,255 @@ impl Transaction {
     }
 
     pub fn deserialize(bytes: &[u8]) -> Transaction {
-        bincode::deserialize(bytes).unwrap()
+        Self::try_deserialize(bytes).unwrap()
+    }
+
+    /// 从不可信的字节数组（如网络数据）反序列化
+    pub fn try_deserialize(bytes: &[u8]) -> Result<Transaction> {
+        Ok(bincode::deserialize(bytes)?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Blockchain, Transaction, UTXOSet};
+    use super::{get_block_subsidy, get_scheduled_supply, TXOutput};
+    use crate::{
+        Block, Blockchain, Error, MemoryPool, MemoryStorage, RejectReason, Transaction, UTXOSet,
+        Wallets, GLOBAL_CONFIG,
+    };
     use data_encoding::HEXLOWER;
+    use std::sync::Arc;
+
+    const OTHER_ADDRESS: &str = "1LecNaLYsDoxRtxBBWKMNbLvccftmFZWcv";
 
     #[test]
     fn new_coinbase_tx() {
         // BTC 创世块: 1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa
-        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
         let txid_hex = HEXLOWER.encode(tx.get_id());
         println!("txid = {}", txid_hex);
     }
 
+    #[test]
+    fn test_block_subsidy() {
+        let interval = GLOBAL_CONFIG.get_subsidy_halving_interval();
+        assert_eq!(get_block_subsidy(0), 10);
+        assert_eq!(get_block_subsidy(interval - 1), 10);
+        assert_eq!(get_block_subsidy(interval), 5);
+        assert_eq!(get_block_subsidy(interval * 3), 1);
+        assert_eq!(get_block_subsidy(interval * 64), 0);
+
+        assert_eq!(get_scheduled_supply(0), 10);
+        assert_eq!(get_scheduled_supply(interval), 10 * interval as i64 + 5);
+        assert_eq!(
+            get_scheduled_supply(interval * 100),
+            (10 + 5 + 2 + 1) * interval as i64
+        );
+    }
+
     #[test]
     fn test_blockchain_serialize() {
-        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 0, 0);
         let tx_bytes = tx.serialize();
         let new_tx = Transaction::deserialize(tx_bytes.as_ref());
         assert_eq!(tx.get_id(), new_tx.get_id())
     }
 
+    /// 创建内存中的区块链，创世块的 coinbase 输出支付给钱包地址 from，再挖出一个区块，
+    /// 其中 from 将创世块奖励转给自己作为资金来源，输出依次为 amount 及找零，返回该交易
+    fn new_funded_blockchain(
+        wallets: &Wallets,
+        from: &str,
+        amount: i32,
+    ) -> (Blockchain, Transaction) {
+        // 创世块奖励需要在下一个区块中被花费
+        GLOBAL_CONFIG.set_coinbase_maturity(1);
+        let blockchain =
+            Blockchain::create_with_storage(Arc::new(MemoryStorage::new()), from).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let funding_tx = Transaction::new_utxo_transaction_with_wallets(
+            wallets, from, from, amount, 0, &utxo_set,
+        )
+        .unwrap();
+        // 挖矿奖励支付给其他地址，不影响 from 的余额
+        let coinbase_tx = Transaction::new_coinbase_tx(OTHER_ADDRESS, 1, 0);
+        blockchain
+            .mine_block(&[funding_tx.clone(), coinbase_tx])
+            .unwrap();
+        (blockchain, funding_tx)
+    }
+
     #[test]
     fn new_utxo_transaction() {
-        let blockchain = Blockchain::new_blockchain();
-        let utxo_set = UTXOSet::new(blockchain);
-        let tx = Transaction::new_utxo_transaction(
-            "13SDifQUyLGCwFjh64vihoWQcGsTozHuQb",
-            "1LecNaLYsDoxRtxBBWKMNbLvccftmFZWcv",
+        let data_dir = crate::temp_data_dir();
+        let mut wallets = Wallets::open(&data_dir).unwrap();
+        let from = wallets.create_wallet();
+        let to = OTHER_ADDRESS;
+
+        let (blockchain, _) = new_funded_blockchain(&wallets, from.as_str(), 10);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        let tx = Transaction::new_utxo_transaction_with_wallets(
+            &wallets,
+            from.as_str(),
+            to,
             5,
+            1,
             &utxo_set,
-        );
+        )
+        .unwrap();
+        assert!(tx.is_id_valid());
+        assert!(tx.verify(&blockchain));
+        assert_eq!(utxo_set.calculate_fee(&tx), Some(1));
         let txid_hex = HEXLOWER.encode(tx.get_id());
-        // b4a0498750e48c431b84e03dd3ec0dc4d9df1b823c45a7e3c59171b8c2f099cd
         println!("txid_hex = {}", txid_hex);
+
+        let result = Transaction::new_utxo_transaction_with_wallets(
+            &wallets,
+            from.as_str(),
+            to,
+            100,
+            1,
+            &utxo_set,
+        );
+        assert!(matches!(result, Err(Error::InsufficientFunds { .. })));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn spend_one_of_multiple_outputs() {
+        let data_dir = crate::temp_data_dir();
+        let mut wallets = Wallets::open(&data_dir).unwrap();
+        let from = wallets.create_wallet();
+        let to = OTHER_ADDRESS;
+
+        let (blockchain, funding_tx) = new_funded_blockchain(&wallets, from.as_str(), 3);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        // 只花费第一个输出
+        let tx = Transaction::new_utxo_transaction_with_wallets(
+            &wallets,
+            from.as_str(),
+            to,
+            3,
+            0,
+            &utxo_set,
+        )
+        .unwrap();
+        assert_eq!(tx.get_vin().len(), 1);
+        assert_eq!(tx.get_vin()[0].get_vout(), 0);
+        let block = blockchain
+            .mine_block(&[tx, Transaction::new_coinbase_tx(to, 2, 0)])
+            .unwrap();
+
+        // 其余输出的索引保持不变，仍然可以被花费
+        assert!(utxo_set.find_output(funding_tx.get_id(), 0).is_none());
+        let output = utxo_set.find_output(funding_tx.get_id(), 1).unwrap();
+        assert_eq!(output.get_value(), 7);
+        let tx = Transaction::new_utxo_transaction_with_wallets(
+            &wallets,
+            from.as_str(),
+            to,
+            5,
+            1,
+            &utxo_set,
+        )
+        .unwrap();
+        assert!(tx.verify(&blockchain));
+        assert_eq!(utxo_set.calculate_fee(&tx), Some(1));
+
+        // 回滚后被花费的输出恢复到原来的位置
+        utxo_set.rollback(&block).unwrap();
+        let output = utxo_set.find_output(funding_tx.get_id(), 0).unwrap();
+        assert_eq!(output.get_value(), 3);
+        assert_eq!(
+            utxo_set
+                .find_output(funding_tx.get_id(), 1)
+                .unwrap()
+                .get_value(),
+            7
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_value_overflow() {
+        let data_dir = crate::temp_data_dir();
+        let mut wallets = Wallets::open(&data_dir).unwrap();
+        let from = wallets.create_wallet();
+        let (blockchain, funding_tx) = new_funded_blockchain(&wallets, from.as_str(), 10);
+        let new_block = |transactions: &[Transaction]| {
+            let tip_header = blockchain
+                .get_block_header(blockchain.get_tip_hash().as_bytes())
+                .unwrap();
+            Block::new_block_at(
+                blockchain.get_tip_hash(),
+                transactions,
+                tip_header.get_height() + 1,
+                blockchain.get_next_bits(&tip_header).unwrap(),
+                blockchain.get_next_timestamp(&tip_header),
+            )
+        };
+        let overflowing_outputs = vec![
+            TXOutput::new(i32::MAX, OTHER_ADDRESS),
+            TXOutput::new(2, OTHER_ADDRESS),
+        ];
+
+        // 输出总额溢出的交易不能通过输出总额不超过输入总额的检查
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let mut tx = Transaction::new_utxo_transaction_with_wallets(
+            &wallets,
+            from.as_str(),
+            OTHER_ADDRESS,
+            10,
+            0,
+            &utxo_set,
+        )
+        .unwrap();
+        tx.vout = overflowing_outputs.clone();
+        let wallet = wallets.get_wallet(from.as_str()).unwrap();
+        tx.sign(&funding_tx.get_vout()[..1], wallet.get_pkcs8());
+        tx.id = tx.hash();
+        assert!(tx.verify_with_outputs(&funding_tx.get_vout()[..1]));
+        assert_eq!(tx.get_output_value(), None);
+        assert_eq!(utxo_set.calculate_fee(&tx), None);
+        assert!(matches!(
+            MemoryPool::new().accept(tx.clone(), &utxo_set),
+            Err(RejectReason::ValueOverflow)
+        ));
+        let coinbase_tx = Transaction::new_coinbase_tx(OTHER_ADDRESS, 2, 0);
+        assert!(matches!(
+            blockchain.validate_block(&new_block(&[tx, coinbase_tx])),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        // coinbase 交易的输出总额溢出
+        let mut coinbase_tx = Transaction::new_coinbase_tx(OTHER_ADDRESS, 2, 0);
+        coinbase_tx.vout = overflowing_outputs;
+        coinbase_tx.id = coinbase_tx.hash();
+        assert!(matches!(
+            blockchain.validate_block(&new_block(&[coinbase_tx])),
+            Err(Error::InvalidBlock(_))
+        ));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_transaction_without_inputs() {
+        let blockchain = Blockchain::create_with_storage(
+            Arc::new(MemoryStorage::new()),
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+        )
+        .unwrap();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![],
+            vout: vec![TXOutput::new(0, OTHER_ADDRESS)],
+        };
+        tx.id = tx.hash();
+        assert!(!tx.is_coinbase());
+        assert!(matches!(
+            MemoryPool::new().accept(tx, &UTXOSet::new(blockchain)),
+            Err(RejectReason::NoInputs)
+        ));
     }
 }
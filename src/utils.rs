@@ -36,8 +36,8 @@ pub fn base58_encode(data: &[u8]) -> String {
 }
 
 /// base58 解码
-pub fn base58_decode(data: &str) -> Vec<u8> {
-    bs58::decode(data).into_vec().unwrap()
+pub fn base58_decode(data: &str) -> crate::Result<Vec<u8>> {
+    Ok(bs58::decode(data).into_vec()?)
 }
 
 /// 创建密钥对（椭圆曲线加密）
@@ -62,6 +62,12 @@ pub fn ecdsa_p256_sha256_sign_verify(public_key: &[u8], signature: &[u8], messag
     result.is_ok()
 }
 
+/// 测试使用的临时数据目录，每次调用返回不同的路径
+#[cfg(test)]
+pub(crate) fn temp_data_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("blockchain_rust_{}", uuid::Uuid::new_v4()))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::new_key_pair;
@@ -91,9 +97,12 @@ mod tests {
         let sign = "dd2324928f0552d4f4c6e57d9e5f6009ab085d85";
         let base58_sign = crate::base58_encode(sign.as_bytes());
 
-        let decode_bytes = crate::base58_decode(base58_sign.as_str());
+        let decode_bytes = crate::base58_decode(base58_sign.as_str()).unwrap();
         let decode_str = String::from_utf8(decode_bytes).unwrap();
         assert_eq!(sign, decode_str.as_str());
+
+        // 0、O、I、l 不在 base58 字母表中
+        assert!(crate::base58_decode("0OIl").is_err());
     }
 
     #[test]
@@ -1,9 +1,78 @@
 use crate::transaction::TXOutput;
-use crate::{Block, Blockchain};
+use crate::{Block, Blockchain, Error, Transaction, WriteBatch, GLOBAL_CONFIG};
 use data_encoding::HEXLOWER;
+use log::info;
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-const UTXO_TREE: &str = "chainstate";
+/// UTXO 集 ( K -> txid + 大端序的输出索引, V -> UTXOEntry )
+const UTXO_TREE: &str = "utxo";
+/// UTXO 集的公钥哈希索引 ( K -> pub_key_hash + txid + 大端序的输出索引, V -> 空 )
+const UTXO_INDEX_TREE: &str = "utxo_index";
+/// UTXO 集已建立的标记，键比公钥哈希短，不会被按公钥哈希前缀查询到
+const UTXO_INDEX_KEY: &str = "indexed";
+/// 区块的撤销数据 ( K -> block_hash, V -> Vec<(outpoint, UTXOEntry)> )
+pub(crate) const UNDO_TREE: &str = "utxo_undo";
+
+/// 未花费输出在 UTXO 集中的记录：输出本身、所在交易的区块高度以及该交易是否为 coinbase 交易
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UTXOEntry {
+    output: TXOutput,
+    height: usize,
+    is_coinbase: bool,
+}
+
+impl UTXOEntry {
+    pub fn new(output: TXOutput, height: usize, is_coinbase: bool) -> UTXOEntry {
+        UTXOEntry {
+            output,
+            height,
+            is_coinbase,
+        }
+    }
+
+    pub fn get_output(&self) -> &TXOutput {
+        &self.output
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.is_coinbase
+    }
+
+    /// 输出能否被高度为 spend_height 的区块花费，coinbase 输出需要等待 coinbase 成熟度个区块
+    pub fn is_mature(&self, spend_height: usize) -> bool {
+        !self.is_coinbase || spend_height >= self.height + GLOBAL_CONFIG.get_coinbase_maturity()
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> UTXOEntry {
+        bincode::deserialize(bytes).expect("unable to deserialize UTXOEntry")
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("unable to serialize UTXOEntry")
+    }
+}
+
+/// 输出在 UTXO 集中的键，txid 之后的输出索引采用大端序，同一交易的输出按索引排列在一起
+fn outpoint_key(txid: &[u8], vout: usize) -> Vec<u8> {
+    [txid, (vout as u32).to_be_bytes().as_slice()].concat()
+}
+
+/// 从 UTXO 集的键中解析出 txid 和输出索引
+fn parse_outpoint_key(key: &[u8]) -> (&[u8], usize) {
+    let (txid, vout) = key.split_at(key.len() - 4);
+    (txid, u32::from_be_bytes(vout.try_into().unwrap()) as usize)
+}
+
+/// 公钥哈希索引的键
+fn index_key(pub_key_hash: &[u8], outpoint: &[u8]) -> Vec<u8> {
+    [pub_key_hash, outpoint].concat()
+}
 
 /// UTXO 集
 pub struct UTXOSet {
@@ -13,14 +82,68 @@ pub struct UTXOSet {
 impl UTXOSet {
     /// 创建 UTXO 集
     pub fn new(blockchain: Blockchain) -> UTXOSet {
-        UTXOSet { blockchain }
+        let utxo_set = UTXOSet { blockchain };
+        utxo_set.init();
+        utxo_set
+    }
+
+    /// UTXO 集尚未建立时从区块重建
+    fn init(&self) {
+        let storage = self.blockchain.get_storage();
+        if storage
+            .get(UTXO_INDEX_TREE, UTXO_INDEX_KEY.as_bytes())
+            .unwrap()
+            .is_some()
+        {
+            return;
+        }
+        self.reindex().expect("unable to build the UTXO set");
+        info!("Built the UTXO set of {} outputs", self.count_outputs());
+    }
+
+    /// 通过公钥哈希索引查找该公钥哈希的未花费输出 ( (txid, vout), UTXOEntry )
+    fn find_entries(&self, pub_key_hash: &[u8]) -> Vec<((Vec<u8>, usize), UTXOEntry)> {
+        let storage = self.blockchain.get_storage();
+        let mut entries = vec![];
+        for item in storage.scan_prefix(UTXO_INDEX_TREE, pub_key_hash).unwrap() {
+            let (k, _) = item.unwrap();
+            let (txid, vout) = parse_outpoint_key(&k[pub_key_hash.len()..]);
+            if let Some(entry) = self.find_entry(txid, vout) {
+                entries.push(((txid.to_vec(), vout), entry));
+            }
+        }
+        entries
+    }
+
+    /// 将输出在 UTXO 集中的记录加入 batch，entry 为 None 时移除记录，同时更新公钥哈希索引
+    /// 原有的记录从存储中读取，同一个 batch 中不能重复修改同一个输出
+    fn put_entry(&self, batch: &mut WriteBatch, outpoint: &[u8], entry: Option<&UTXOEntry>) {
+        let storage = self.blockchain.get_storage();
+        if let Some(old_bytes) = storage.get(UTXO_TREE, outpoint).unwrap() {
+            let old_entry = UTXOEntry::deserialize(old_bytes.as_slice());
+            batch.remove(
+                UTXO_INDEX_TREE,
+                index_key(old_entry.get_output().get_pub_key_hash(), outpoint),
+            );
+        }
+        match entry {
+            Some(entry) => {
+                batch.insert(
+                    UTXO_INDEX_TREE,
+                    index_key(entry.get_output().get_pub_key_hash(), outpoint),
+                    [],
+                );
+                batch.insert(UTXO_TREE, outpoint, entry.serialize());
+            }
+            None => batch.remove(UTXO_TREE, outpoint),
+        }
     }
 
     pub fn get_blockchain(&self) -> &Blockchain {
         &self.blockchain
     }
 
-    /// 找到未花费的输出
+    /// 找到未花费的输出，未成熟的 coinbase 输出不能用于下一个区块，会被跳过
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
@@ -28,112 +151,271 @@ impl UTXOSet {
     ) -> (i32, HashMap<String, Vec<usize>>) {
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
         let mut accmulated = 0;
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TXOutput");
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accmulated < amount {
-                    accmulated += out.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
-                }
+        let spend_height = self.blockchain.get_best_height() + 1;
+        for ((txid, vout), entry) in self.find_entries(pub_key_hash) {
+            if accmulated >= amount {
+                break;
+            }
+            if !entry.is_mature(spend_height) {
+                continue;
             }
+            accmulated += entry.get_output().get_value();
+            unspent_outputs
+                .entry(HEXLOWER.encode(txid.as_slice()))
+                .or_default()
+                .push(vout);
         }
         (accmulated, unspent_outputs)
     }
 
+    /// 统计公钥哈希的余额，返回 (下一个区块可以花费的金额, 尚未成熟的 coinbase 输出金额)
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> (i32, i32) {
+        let spend_height = self.blockchain.get_best_height() + 1;
+        let mut spendable = 0;
+        let mut immature = 0;
+        for (_, entry) in self.find_entries(pub_key_hash) {
+            if entry.is_mature(spend_height) {
+                spendable += entry.get_output().get_value();
+            } else {
+                immature += entry.get_output().get_value();
+            }
+        }
+        (spendable, immature)
+    }
+
     /// 通过公钥哈希查找 UTXO 集
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let mut utxos = vec![];
-        for item in utxo_tree.iter() {
-            let (_, v) = item.unwrap();
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TXOutput");
-            for out in outs.iter() {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone())
-                }
-            }
+        self.find_entries(pub_key_hash)
+            .into_iter()
+            .map(|(_, entry)| entry.output)
+            .collect()
+    }
+
+    /// 查找输出在 UTXO 集中的记录，输出不存在或已被花费时返回 None
+    pub fn find_entry(&self, txid: &[u8], vout: usize) -> Option<UTXOEntry> {
+        let storage = self.blockchain.get_storage();
+        let entry_bytes = storage
+            .get(UTXO_TREE, outpoint_key(txid, vout).as_slice())
+            .unwrap()?;
+        Some(UTXOEntry::deserialize(entry_bytes.as_slice()))
+    }
+
+    /// 查找交易输入引用的未花费输出
+    pub fn find_output(&self, txid: &[u8], vout: usize) -> Option<TXOutput> {
+        let entry = self.find_entry(txid, vout)?;
+        Some(entry.output)
+    }
+
+    /// 交易是否还有未花费的输出
+    pub fn has_unspent_outputs(&self, txid: &[u8]) -> bool {
+        let storage = self.blockchain.get_storage();
+        storage
+            .scan_prefix(UTXO_TREE, txid)
+            .unwrap()
+            .next()
+            .is_some()
+    }
+
+    /// 计算交易的手续费，即输入总额减去输出总额，输入不在 UTXO 集中或金额溢出时返回 None
+    pub fn calculate_fee(&self, tx: &Transaction) -> Option<i32> {
+        if tx.is_coinbase() {
+            return Some(0);
+        }
+        let mut input_value: i32 = 0;
+        for vin in tx.get_vin() {
+            let output = self.find_output(vin.get_txid(), vin.get_vout())?;
+            input_value = input_value.checked_add(output.get_value())?;
         }
-        utxos
+        input_value.checked_sub(tx.get_output_value()?)
     }
 
     /// 统计 UTXO 集合中的交易数量
     pub fn count_transactions(&self) -> i32 {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let storage = self.blockchain.get_storage();
+        let mut counter = 0;
+        let mut last_txid: Vec<u8> = vec![];
+        // 同一交易的输出按键排列在一起
+        for item in storage.iter(UTXO_TREE).unwrap() {
+            let (k, _) = item.unwrap();
+            let (txid, _) = parse_outpoint_key(k.as_slice());
+            if txid.ne(last_txid.as_slice()) {
+                counter += 1;
+                last_txid = txid.to_vec();
+            }
+        }
+        counter
+    }
+
+    /// 统计 UTXO 集合中未花费输出的数量
+    pub fn count_outputs(&self) -> i32 {
+        let storage = self.blockchain.get_storage();
         let mut counter = 0;
-        for _ in utxo_tree.iter() {
+        for _ in storage.iter(UTXO_TREE).unwrap() {
             counter += 1;
         }
         counter
     }
 
+    /// 统计 UTXO 集合中的货币总量
+    pub fn get_total_amount(&self) -> i64 {
+        let storage = self.blockchain.get_storage();
+        let mut amount = 0;
+        for item in storage.iter(UTXO_TREE).unwrap() {
+            let (_, v) = item.unwrap();
+            let entry = UTXOEntry::deserialize(v.as_slice());
+            amount += entry.get_output().get_value() as i64;
+        }
+        amount
+    }
+
+    /// 计算 UTXO 集的哈希，按键的顺序对所有输出的键和记录计算 SHA-256
+    pub fn get_hash(&self) -> Vec<u8> {
+        let storage = self.blockchain.get_storage();
+        let mut context = Context::new(&SHA256);
+        for item in storage.iter(UTXO_TREE).unwrap() {
+            let (k, v) = item.unwrap();
+            context.update(k.as_slice());
+            context.update(v.as_slice());
+        }
+        context.finish().as_ref().to_vec()
+    }
+
+    /// 从区块重新计算 UTXO 集，返回其哈希，用于检查 UTXO 集与区块是否一致
+    pub fn compute_hash(&self) -> crate::Result<Vec<u8>> {
+        let mut items: Vec<(Vec<u8>, Vec<u8>)> = self
+            .blockchain
+            .find_utxo()?
+            .iter()
+            .map(|((txid_hex, vout), entry)| {
+                let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
+                (outpoint_key(txid.as_slice(), *vout), entry.serialize())
+            })
+            .collect();
+        items.sort();
+        let mut context = Context::new(&SHA256);
+        for (k, v) in &items {
+            context.update(k.as_slice());
+            context.update(v.as_slice());
+        }
+        Ok(context.finish().as_ref().to_vec())
+    }
+
     /// 重建 UTXO 集
-    pub fn reindex(&self) {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let _ = utxo_tree.clear().unwrap();
+    pub fn reindex(&self) -> crate::Result<()> {
+        let storage = self.blockchain.get_storage();
+        storage.clear(UTXO_TREE)?;
+        storage.clear(UTXO_INDEX_TREE)?;
 
-        let utxo_map = self.blockchain.find_utxo();
-        for (txid_hex, outs) in &utxo_map {
+        let mut batch = WriteBatch::new();
+        let utxo_map = self.blockchain.find_utxo()?;
+        for ((txid_hex, vout), entry) in &utxo_map {
             let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            let value = bincode::serialize(outs).unwrap();
-            let _ = utxo_tree.insert(txid.as_slice(), value).unwrap();
+            self.put_entry(
+                &mut batch,
+                outpoint_key(txid.as_slice(), *vout).as_slice(),
+                Some(entry),
+            );
         }
+        batch.insert(UTXO_INDEX_TREE, UTXO_INDEX_KEY, []);
+        storage.write_batch(batch)
     }
 
-    /// 使用来自区块的交易更新 UTXO 集
-    pub fn update(&self, block: &Block) {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+    /// 使用来自区块的交易更新 UTXO 集，同时保存该区块的撤销数据
+    pub fn update(&self, block: &Block) -> crate::Result<()> {
+        let mut batch = WriteBatch::new();
+        self.connect_block(&mut batch, block)?;
+        self.blockchain.get_storage().write_batch(batch)
+    }
+
+    /// 将区块对 UTXO 集的修改及区块的撤销数据加入 batch，以便与最新区块的修改一起原子地写入
+    pub fn connect_block(&self, batch: &mut WriteBatch, block: &Block) -> crate::Result<()> {
+        // 区块对 UTXO 集的修改，后面的交易可以花费前面的交易产生的输出
+        let mut changes: HashMap<Vec<u8>, Option<UTXOEntry>> = HashMap::new();
+        // 被区块花费的输出在连接区块之前的记录
+        let mut undo: Vec<(Vec<u8>, UTXOEntry)> = vec![];
         for tx in block.get_transactions() {
-            if tx.is_coinbase() == false {
+            if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-                    let outs_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TXOutput> = bincode::deserialize(outs_bytes.as_ref())
-                        .expect("unable to deserialize TXOutput");
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone())
+                    let outpoint = outpoint_key(vin.get_txid(), vin.get_vout());
+                    match changes.insert(outpoint.clone(), None) {
+                        // 同一区块中产生的输出回滚时会被直接移除，不需要撤销数据
+                        Some(Some(_)) => {}
+                        Some(None) => {
+                            return Err(Error::InvalidBlock(String::from(
+                                "output is spent twice in the block",
+                            )))
+                        }
+                        None => {
+                            let entry = self
+                                .find_entry(vin.get_txid(), vin.get_vout())
+                                .ok_or_else(|| {
+                                    Error::InvalidBlock(String::from(
+                                        "input is already spent or does not exist",
+                                    ))
+                                })?;
+                            undo.push((outpoint, entry));
                         }
-                    }
-                    if updated_outs.len() == 0 {
-                        let _ = utxo_tree.remove(vin.get_txid()).unwrap();
-                    } else {
-                        let outs_bytes = bincode::serialize(&updated_outs)
-                            .expect("unable to serialize TXOutput");
-                        utxo_tree.insert(vin.get_txid(), outs_bytes).unwrap();
                     }
                 }
             }
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone())
+            for (vout, out) in tx.get_vout().iter().enumerate() {
+                let entry = UTXOEntry::new(out.clone(), block.get_height(), tx.is_coinbase());
+                changes.insert(outpoint_key(tx.get_id(), vout), Some(entry));
+            }
+        }
+        for (outpoint, entry) in &changes {
+            self.put_entry(batch, outpoint.as_slice(), entry.as_ref());
+        }
+        let undo_bytes = bincode::serialize(&undo).expect("unable to serialize undo data");
+        batch.insert(UNDO_TREE, block.get_hash(), undo_bytes);
+        Ok(())
+    }
+
+    /// 使用区块的撤销数据回滚 UTXO 集，即从 UTXO 集中断开该区块
+    pub fn rollback(&self, block: &Block) -> crate::Result<()> {
+        let mut batch = WriteBatch::new();
+        self.disconnect_block(&mut batch, block)?;
+        self.blockchain.get_storage().write_batch(batch)?;
+        Ok(())
+    }
+
+    /// 将使用撤销数据回滚区块的修改加入 batch，以便与最新区块的修改一起原子地写入
+    pub fn disconnect_block(&self, batch: &mut WriteBatch, block: &Block) -> crate::Result<()> {
+        let storage = self.blockchain.get_storage();
+        let undo_bytes = storage
+            .get(UNDO_TREE, block.get_hash().as_bytes())
+            .unwrap()
+            .ok_or_else(|| {
+                Error::Corrupted(format!(
+                    "undo data of block {} is not found",
+                    block.get_hash()
+                ))
+            })?;
+        let undo: Vec<(Vec<u8>, UTXOEntry)> =
+            bincode::deserialize(undo_bytes.as_slice()).expect("unable to deserialize undo data");
+        // 移除区块产生的输出
+        for tx in block.get_transactions() {
+            for vout in 0..tx.get_vout().len() {
+                self.put_entry(batch, outpoint_key(tx.get_id(), vout).as_slice(), None);
             }
-            let outs_bytes =
-                bincode::serialize(&new_outputs).expect("unable to serialize TXOutput");
-            let _ = utxo_tree.insert(tx.get_id(), outs_bytes).unwrap();
         }
+        // 恢复区块花费的输出
+        for (outpoint, entry) in &undo {
+            self.put_entry(batch, outpoint.as_slice(), Some(entry));
+        }
+        batch.remove(UNDO_TREE, block.get_hash());
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{validate_address, Blockchain, UTXOSet};
+    use super::{UTXOEntry, UTXO_INDEX_TREE};
+    use crate::{
+        decode_address, get_block_subsidy, validate_address, Block, Blockchain, MemoryStorage,
+        Transaction, UTXOSet, GLOBAL_CONFIG,
+    };
+    use std::sync::Arc;
 
     #[test]
     fn test_get_balance() {
@@ -141,17 +423,79 @@ mod tests {
         if validate_address(address) == false {
             panic!("The address is not valid")
         }
-        let payload = crate::base58_decode(address);
+        let payload = crate::base58_decode(address).unwrap();
         let pub_key_hash = &payload[1..payload.len() - crate::ADDRESS_CHECK_SUM_LEN];
 
-        let blockchain = Blockchain::new_blockchain();
+        let blockchain =
+            Blockchain::create_with_storage(Arc::new(MemoryStorage::new()), address).unwrap();
         let utxo_set = UTXOSet::new(blockchain);
+        utxo_set.reindex().unwrap();
 
         let utxos = utxo_set.find_utxo(pub_key_hash);
         let mut balance = 0;
         for utxo in utxos {
             balance += utxo.get_value();
         }
-        println!("The address {} balance is {}", address, balance)
+        assert_eq!(balance, get_block_subsidy(0));
+
+        // 成熟度为 1 时，创世块的 coinbase 输出在高度为 1 的区块中即可被花费
+        GLOBAL_CONFIG.set_coinbase_maturity(1);
+        let (spendable, immature) = utxo_set.get_balance(pub_key_hash);
+        assert_eq!(spendable, get_block_subsidy(0));
+        assert_eq!(immature, 0);
+    }
+
+    #[test]
+    fn test_pub_key_hash_index() {
+        let address = "13SDifQUyLGCwFjh64vihoWQcGsTozHuQb";
+        let other_address = "1LecNaLYsDoxRtxBBWKMNbLvccftmFZWcv";
+        let pub_key_hash = decode_address(address).unwrap();
+        let other_pub_key_hash = decode_address(other_address).unwrap();
+        let blockchain =
+            Blockchain::create_with_storage(Arc::new(MemoryStorage::new()), address).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.find_utxo(pub_key_hash.as_slice()).len(), 1);
+
+        // 连接区块时索引新的输出，回滚后移除
+        let tip_block = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap()
+            .unwrap();
+        let block = Block::new_block_at(
+            blockchain.get_tip_hash(),
+            &[Transaction::new_coinbase_tx(other_address, 1, 0)],
+            1,
+            blockchain.get_next_bits(tip_block.get_header()).unwrap(),
+            blockchain.get_next_timestamp(tip_block.get_header()),
+        );
+        blockchain.add_block(&block).unwrap();
+        let utxos = utxo_set.find_utxo(other_pub_key_hash.as_slice());
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].get_value(), get_block_subsidy(1));
+        utxo_set.rollback(&block).unwrap();
+        assert!(utxo_set.find_utxo(other_pub_key_hash.as_slice()).is_empty());
+        assert_eq!(utxo_set.find_utxo(pub_key_hash.as_slice()).len(), 1);
+
+        // 没有建立标记的旧数据库在创建 UTXO 集时重建
+        blockchain.get_storage().clear(UTXO_INDEX_TREE).unwrap();
+        assert!(utxo_set.find_utxo(pub_key_hash.as_slice()).is_empty());
+        let utxo_set = UTXOSet::new(blockchain);
+        assert_eq!(utxo_set.find_utxo(pub_key_hash.as_slice()).len(), 1);
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        // 测试中统一使用成熟度 1，避免与其他测试并发修改全局配置时相互影响
+        GLOBAL_CONFIG.set_coinbase_maturity(1);
+        let maturity = 1;
+        let tx = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", 5, 0);
+        let coinbase_entry = UTXOEntry::new(tx.get_vout()[0].clone(), 5, true);
+        assert!(!coinbase_entry.is_mature(5 + maturity - 1));
+        assert!(coinbase_entry.is_mature(5 + maturity));
+
+        // 普通交易的输出可以立即被花费
+        let entry = UTXOEntry::new(tx.get_vout()[0].clone(), 5, false);
+        assert!(entry.is_mature(6));
     }
 }
@@ -1,3 +1,4 @@
+use crate::{Error, Result};
 use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
 
@@ -60,16 +61,22 @@ fn checksum(payload: &[u8]) -> Vec<u8> {
 
 /// 验证地址有效
 pub fn validate_address(address: &str) -> bool {
-    let payload = crate::base58_decode(address);
-    let actual_checksum = payload[payload.len() - ADDRESS_CHECK_SUM_LEN..].to_vec();
-    let version = payload[0];
-    let pub_key_hash = payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec();
+    decode_address(address).is_ok()
+}
 
-    let mut target_vec = vec![];
-    target_vec.push(version);
-    target_vec.extend(pub_key_hash);
-    let target_checksum = checksum(target_vec.as_slice());
-    actual_checksum.eq(target_checksum.as_slice())
+/// 验证地址并取出其中的公钥哈希
+pub fn decode_address(address: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidAddress(String::from(address));
+    let payload = crate::base58_decode(address).map_err(|_| invalid())?;
+    // version + pub_key_hash + checksum
+    if payload.len() <= 1 + ADDRESS_CHECK_SUM_LEN {
+        return Err(invalid());
+    }
+    let (versioned, actual_checksum) = payload.split_at(payload.len() - ADDRESS_CHECK_SUM_LEN);
+    if checksum(versioned).ne(actual_checksum) {
+        return Err(invalid());
+    }
+    Ok(versioned[1..].to_vec())
 }
 
 /// 通过公钥哈希计算地址
@@ -97,5 +104,12 @@ mod tests {
         // BTC 创世块：1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa
         let valid = validate_address("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
         assert!(valid);
+
+        // 过短或包含非法字符的地址
+        assert!(!validate_address(""));
+        assert!(!validate_address("1A1z"));
+        assert!(!validate_address("0A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        // 校验和不匹配
+        assert!(!validate_address("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"));
     }
 }
@@ -1,22 +1,34 @@
-use crate::Wallet;
+use crate::{Result, Wallet, GLOBAL_CONFIG};
 use std::collections::HashMap;
-use std::env::current_dir;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
 pub const WALLET_FILE: &str = "wallet.dat";
 
 pub struct Wallets {
+    path: PathBuf, // 钱包文件路径
     wallets: HashMap<String, Wallet>,
 }
 
 impl Wallets {
     pub fn new() -> Wallets {
+        Self::try_new().expect("unable to load wallet.dat")
+    }
+
+    /// 加载默认数据目录中的钱包文件，文件无法读取或已损坏时返回错误
+    pub fn try_new() -> Result<Wallets> {
+        Self::open(GLOBAL_CONFIG.get_data_dir())
+    }
+
+    /// 加载指定数据目录中的钱包文件，文件不存在时返回空的钱包集合
+    pub fn open<P: AsRef<Path>>(data_dir: P) -> Result<Wallets> {
         let mut wallets = Wallets {
+            path: data_dir.as_ref().join(WALLET_FILE),
             wallets: HashMap::new(),
         };
-        wallets.load_from_file();
-        return wallets;
+        wallets.load_from_file()?;
+        Ok(wallets)
     }
 
     /// 创建一个钱包
@@ -45,26 +57,26 @@ impl Wallets {
     }
 
     /// 从本地文件加载钱包
-    pub fn load_from_file(&mut self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
-        if !path.exists() {
-            return;
+    pub fn load_from_file(&mut self) -> Result<()> {
+        if !self.path.exists() {
+            return Ok(());
         }
-        let mut file = File::open(path).unwrap();
-        let metadata = file.metadata().expect("unable to read metadata");
-        let mut buf = vec![0; metadata.len() as usize];
-        let _ = file.read(&mut buf).expect("buffer overflow");
-        let wallets = bincode::deserialize(&buf[..]).expect("unable to deserialize file data");
-        self.wallets = wallets;
+        let mut file = File::open(&self.path)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        self.wallets = bincode::deserialize(&buf[..])?;
+        Ok(())
     }
 
     /// 钱包持久化到本地文件
     fn save_to_file(&self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).expect("unable to create the data directory");
+        }
         let file = OpenOptions::new()
             .create(true)
             .write(true)
-            .open(&path)
+            .open(&self.path)
             .expect("unable to open wallet.dat");
         let mut writer = BufWriter::new(file);
         let wallets_bytes = bincode::serialize(&self.wallets).expect("unable to serialize wallets");
@@ -84,6 +96,19 @@ mod tests {
         println!("The new wallet address is {}", address);
     }
 
+    #[test]
+    fn test_open_data_dir() {
+        let data_dir = crate::temp_data_dir();
+        let mut wallets = Wallets::open(&data_dir).unwrap();
+        assert!(wallets.get_addresses().is_empty());
+        let address = wallets.create_wallet();
+
+        let wallets = Wallets::open(&data_dir).unwrap();
+        assert_eq!(wallets.get_addresses(), vec![address.clone()]);
+        assert!(wallets.get_wallet(address.as_str()).is_some());
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
     #[test]
     fn test_get_addresses() {
         let addresses = Wallets::new().get_addresses();
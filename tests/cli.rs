@@ -49,6 +49,7 @@ fn client_get_balance() {
 fn client_send() {
     Command::cargo_bin(env!("CARGO_PKG_NAME"))
         .unwrap()
+        .env("COINBASE_MATURITY", "1")
         .arg("send")
         .arg("1NA3ZoWS1xHkvhTPXU4PEX9ABR5gJ1CHMR")
         .arg("1PipUzybS5DcMvNQe3XMiLML9z7fZdpz35")
@@ -58,6 +59,22 @@ fn client_send() {
         .success();
 }
 
+#[test]
+fn client_send_with_fee() {
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .env("COINBASE_MATURITY", "1")
+        .arg("send")
+        .arg("1NA3ZoWS1xHkvhTPXU4PEX9ABR5gJ1CHMR")
+        .arg("1PipUzybS5DcMvNQe3XMiLML9z7fZdpz35")
+        .arg("5")
+        .arg("1")
+        .arg("--fee")
+        .arg("1")
+        .assert()
+        .success();
+}
+
 #[test]
 fn client_printchain() {
     let command = Command::cargo_bin(env!("CARGO_PKG_NAME"))
@@ -79,6 +96,18 @@ fn client_reindexutxo() {
         .success();
 }
 
+#[test]
+fn client_gettxoutsetinfo() {
+    let command = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("gettxoutsetinfo")
+        .assert()
+        .success();
+
+    let output_bytes = command.get_output().stdout.as_slice();
+    println!("{}", String::from_utf8(output_bytes.to_vec()).unwrap())
+}
+
 #[test]
 fn client_startnode() {
     Command::cargo_bin(env!("CARGO_PKG_NAME"))